@@ -0,0 +1,299 @@
+use crate::brightcove::PLAYBACK_NAME;
+use crate::hls::{HLS_DESTINATION_PREFIX, PARTS_SUFFIX, SEGMENTS_SUFFIX};
+use crate::quality::QualityPolicy;
+use crate::subtitles::SubtitleMode;
+use crate::video_meta::VIDEO_META_NAME;
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+use strum::AsRefStr;
+
+/// External program doing the actual media download
+#[derive(Clone, Default, Debug)]
+pub enum DownloaderBackend {
+    #[default]
+    YoutubeDl,
+    YtDlp,
+    /// Whitespace separated program and args with {url} {output_dir} {id} {title} {format}
+    /// placeholders, {format} being the youtube-dl format selector of the quality policy
+    Custom {
+        template: Vec<String>,
+    },
+    /// Built in HLS fetcher using the Brightcove playback API
+    Native,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ArtifactKind {
+    InfoJson,
+    Chapters,
+    Media,
+    Thumbnail,
+    Subtitle,
+    Description,
+}
+
+/// One kind of file a backend leaves in a video directory, matched by name suffix
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub suffixes: &'static [&'static str],
+    pub required: bool,
+}
+
+/// What a finished video directory looks like for a backend
+pub struct ArtifactSpec {
+    /// First match wins, so longer suffixes like .info.json go before .json ones
+    pub artifacts: Vec<Artifact>,
+    /// Files that don't make a video complete or incomplete
+    pub bookkeeping: &'static [&'static str],
+}
+
+const MEDIA_SUFFIXES: &[&str] = &[
+    ".mp4", ".mkv", ".webm", ".ts", ".mov", ".m4v", ".m4a", ".flv", ".avi", ".mp3", ".opus",
+];
+const IMAGE_SUFFIXES: &[&str] = &[".jpg", ".jpeg", ".png", ".webp", ".gif"];
+const SUBTITLE_SUFFIXES: &[&str] = &[".vtt", ".srt", ".ass", ".ttml", ".srv3"];
+
+const YTDL_ARGS: [&str; 3] = ["--write-info-json", "--write-thumbnail", "--verbose"];
+
+static TEMPLATE_PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(url|output_dir|id|title|format)\}").unwrap());
+static YOUTUBE_DL_DESTINATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\[download\] (?:Destination: (.+)|(.+) has already been downloaded)$")
+        .unwrap()
+});
+static YT_DLP_DESTINATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?m)^(?:\[Merger\] Merging formats into "(.+)"|\[download\] Destination: (.+)|\[download\] (.+) has already been downloaded)$"#,
+    )
+    .unwrap()
+});
+
+impl DownloaderBackend {
+    pub fn from_config(name: Option<&str>, template: Option<&str>) -> Self {
+        match name.unwrap_or("youtube-dl") {
+            "youtube-dl" => Self::YoutubeDl,
+            "yt-dlp" => Self::YtDlp,
+            "custom" => {
+                let template: Vec<String> = template
+                    .expect("DOWNLOADER=custom needs DOWNLOADER_TEMPLATE")
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect();
+                assert!(
+                    template.iter().any(|arg| arg.contains("{url}")),
+                    "DOWNLOADER_TEMPLATE without {{url}}"
+                );
+                Self::Custom { template }
+            }
+            "native" => Self::Native,
+            unknown => panic!("unknown DOWNLOADER {unknown}"),
+        }
+    }
+
+    pub fn program(&self) -> &str {
+        match self {
+            Self::YoutubeDl => "youtube-dl",
+            Self::YtDlp => "yt-dlp",
+            Self::Custom { template } => &template[0],
+            // never spawned
+            Self::Native => "native-hls",
+        }
+    }
+
+    /// Run from inside the output dir
+    pub fn args(
+        &self,
+        url: &str,
+        output_dir: &str,
+        id: &str,
+        title: &str,
+        quality: &QualityPolicy,
+        subtitles: SubtitleMode,
+    ) -> Vec<String> {
+        match self {
+            Self::YoutubeDl => YTDL_ARGS
+                .iter()
+                .map(|arg| arg.to_string())
+                .chain(quality.ytdl_args())
+                .chain(subtitles.ytdl_args(false))
+                .chain([url.into()])
+                .collect(),
+            // keep the log free of progress bar carriage returns
+            Self::YtDlp => YTDL_ARGS
+                .iter()
+                .map(|arg| arg.to_string())
+                .chain(quality.ytdl_args())
+                .chain(subtitles.ytdl_args(true))
+                .chain(["--newline".into(), url.into()])
+                .collect(),
+            // no shell involved, so the values need no quoting. One pass, a title saying {id}
+            // stays as it is
+            Self::Custom { template } => {
+                let format = quality.ytdl_format();
+                template[1..]
+                    .iter()
+                    .map(|arg| {
+                        TEMPLATE_PLACEHOLDER
+                            .replace_all(arg, |field: &Captures| match &field[1] {
+                                "url" => url,
+                                "output_dir" => output_dir,
+                                "id" => id,
+                                "title" => title,
+                                "format" => format.as_deref().unwrap_or("best"),
+                                field => unreachable!("matched field {field}"),
+                            })
+                            .into_owned()
+                    })
+                    .collect()
+            }
+            Self::Native => Vec::new(),
+        }
+    }
+
+    pub fn artifacts(&self) -> ArtifactSpec {
+        let artifact = |kind, suffixes, required| Artifact {
+            kind,
+            suffixes,
+            required,
+        };
+        let ytdl_like = |chapters| {
+            let mut artifacts = vec![
+                artifact(ArtifactKind::InfoJson, &[".info.json"], true),
+                artifact(ArtifactKind::Media, MEDIA_SUFFIXES, true),
+                artifact(ArtifactKind::Thumbnail, IMAGE_SUFFIXES, true),
+                artifact(ArtifactKind::Subtitle, SUBTITLE_SUFFIXES, false),
+                artifact(ArtifactKind::Description, &[".description"], false),
+            ];
+            if chapters {
+                artifacts.insert(
+                    1,
+                    artifact(ArtifactKind::Chapters, &[".chapters.json"], false),
+                );
+            }
+            artifacts
+        };
+        match self {
+            Self::YoutubeDl => ArtifactSpec {
+                artifacts: ytdl_like(false),
+                bookkeeping: &[VIDEO_META_NAME],
+            },
+            Self::YtDlp => ArtifactSpec {
+                artifacts: ytdl_like(true),
+                bookkeeping: &[VIDEO_META_NAME],
+            },
+            // no idea what else the program writes
+            Self::Custom { .. } => ArtifactSpec {
+                artifacts: vec![
+                    artifact(ArtifactKind::InfoJson, &[".info.json"], false),
+                    artifact(ArtifactKind::Media, MEDIA_SUFFIXES, true),
+                    artifact(ArtifactKind::Thumbnail, IMAGE_SUFFIXES, false),
+                    artifact(ArtifactKind::Subtitle, SUBTITLE_SUFFIXES, false),
+                ],
+                bookkeeping: &[VIDEO_META_NAME],
+            },
+            // not every video has a poster. A separate audio track is a second media file
+            Self::Native => ArtifactSpec {
+                artifacts: vec![
+                    artifact(ArtifactKind::InfoJson, &[".info.json"], true),
+                    artifact(ArtifactKind::Media, &[".ts", ".mp4"], true),
+                    artifact(ArtifactKind::Thumbnail, &[".jpg"], false),
+                    artifact(ArtifactKind::Subtitle, &[".vtt", ".srt"], false),
+                ],
+                bookkeeping: &[
+                    ".m3u8",
+                    SEGMENTS_SUFFIX,
+                    PARTS_SUFFIX,
+                    PLAYBACK_NAME,
+                    VIDEO_META_NAME,
+                ],
+            },
+        }
+    }
+
+    /// Final media file name according to the downloader's own log
+    pub fn parse_media_file(&self, log: &str) -> Option<String> {
+        let regex = match self {
+            Self::YoutubeDl => &YOUTUBE_DL_DESTINATION,
+            Self::YtDlp => &YT_DLP_DESTINATION,
+            Self::Custom { .. } => return None,
+            Self::Native => {
+                let line = log
+                    .lines()
+                    .rev()
+                    .find_map(|line| line.strip_prefix(HLS_DESTINATION_PREFIX))?;
+                return Some(line.trim().to_string());
+            }
+        };
+        // the last one wins, yt-dlp merges separate audio and video downloads at the end
+        let captures = regex.captures_iter(log).last()?;
+        let found = captures.iter().skip(1).flatten().next()?;
+        Some(found.as_str().trim().to_string())
+    }
+}
+
+impl ArtifactSpec {
+    pub fn classify(&self, name: &str) -> Option<ArtifactKind> {
+        let name = name.to_ascii_lowercase();
+        self.artifacts
+            .iter()
+            .find(|a| a.suffixes.iter().any(|suffix| name.ends_with(suffix)))
+            .map(|a| a.kind)
+    }
+
+    pub fn is_bookkeeping(&self, name: &str) -> bool {
+        self.bookkeeping.iter().any(|suffix| name.ends_with(suffix))
+    }
+
+    /// First file of that kind, by name
+    pub fn find<'n>(&self, names: &'n [String], kind: ArtifactKind) -> Option<&'n String> {
+        let mut found: Vec<&String> = names
+            .iter()
+            .filter(|name| self.classify(name) == Some(kind))
+            .collect();
+        found.sort();
+        found.first().copied()
+    }
+
+    pub fn missing_required(&self, names: &[String]) -> Vec<ArtifactKind> {
+        self.artifacts
+            .iter()
+            .filter(|a| a.required && self.find(names, a.kind).is_none())
+            .map(|a| a.kind)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_template_substitutes_once() {
+        let backend = DownloaderBackend::from_config(
+            Some("custom"),
+            Some("dl --out {output_dir}/{id} --name {title}.{unknown} -f {format} {url}"),
+        );
+        assert_eq!(backend.program(), "dl");
+        let args = backend.args(
+            "https://example.com/v?x={id}",
+            "vid-dl/63",
+            "63",
+            "Why {output_dir} {title}?",
+            &QualityPolicy::default(),
+            SubtitleMode::None,
+        );
+        assert_eq!(
+            args,
+            [
+                "--out",
+                "vid-dl/63/63",
+                "--name",
+                "Why {output_dir} {title}?.{unknown}",
+                "-f",
+                "best",
+                "https://example.com/v?x={id}",
+            ]
+        );
+    }
+}
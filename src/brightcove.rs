@@ -0,0 +1,207 @@
+use crate::downloader::{DownType, Downloader};
+use crate::err::{SError, SResult};
+use crate::hls::download_hls;
+use crate::quality::QualityPolicy;
+use crate::subtitles::{
+    PlaybackTextTrack, SubtitleMode, download_text_tracks, extract_text_tracks, record_text_tracks,
+};
+use crate::video_dl::DownloadJob;
+use crate::video_meta::{Rendition, VideoMeta};
+use chrono::NaiveDate;
+use reqwest::Url;
+use serde::Serialize;
+use simd_json::BorrowedValue;
+use simd_json::prelude::{ValueAsArray, ValueObjectAccess, ValueObjectAccessAsScalar};
+use std::fs::File;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Raw playback API response, kept next to the media
+pub const PLAYBACK_NAME: &str = "playback.json";
+
+/// The parts of a Brightcove playback API response we use
+pub struct PlaybackInfo {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// ISO 8601
+    pub published_at: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub poster: Option<String>,
+    pub hls_url: Option<String>,
+    /// Progressive MP4 copies, only recorded in the inventory
+    pub mp4_sources: Vec<Rendition>,
+    pub text_tracks: Vec<PlaybackTextTrack>,
+}
+
+/// Same fields browse reads from youtube-dl's info.json
+#[derive(Serialize)]
+struct InfoJson<'a> {
+    id: &'a str,
+    title: &'a str,
+    fulltitle: &'a str,
+    /// YYYYMMDD, left out when the playback info has no usable date
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_date: Option<String>,
+    description: Option<&'a str>,
+    duration: Option<f64>,
+    extractor: &'a str,
+}
+
+/// 2024-01-05T02:00:00.000Z to 20240105, None for anything not starting with a date
+fn upload_date(published_at: &str) -> Option<String> {
+    let day = published_at.get(0..10)?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    Some(day.replace('-', ""))
+}
+
+pub fn extract_playback(mut content: Vec<u8>) -> SResult<PlaybackInfo> {
+    let json: BorrowedValue = simd_json::to_borrowed_value(&mut content).unwrap();
+    let string = |key: &str| json.get_str(key).map(String::from);
+
+    let sources = json
+        .get("sources")
+        .and_then(|v| v.as_array())
+        .expect("sources");
+    // prefer https, some accounts still list plain http copies
+    let mut hls_urls: Vec<&str> = sources
+        .iter()
+        .filter(|source| {
+            source.get_str("type").is_some_and(|t| {
+                t.eq_ignore_ascii_case("application/x-mpegURL")
+                    || t.eq_ignore_ascii_case("application/vnd.apple.mpegurl")
+            })
+        })
+        .filter_map(|source| source.get_str("src"))
+        .collect();
+    hls_urls.sort_by_key(|url| !url.starts_with("https://"));
+
+    // each is listed once per protocol
+    let mp4_sources = sources
+        .iter()
+        .filter(|source| {
+            source
+                .get_str("container")
+                .is_some_and(|c| c.eq_ignore_ascii_case("MP4"))
+                && source
+                    .get_str("src")
+                    .is_some_and(|src| src.starts_with("https://"))
+        })
+        .map(|source| Rendition {
+            source: "mp4".into(),
+            width: source.get_u64("width").map(|v| v as u32),
+            height: source.get_u64("height").map(|v| v as u32),
+            bitrate: source.get_u64("avg_bitrate"),
+            size: source.get_u64("size"),
+            codecs: source.get_str("codec").map(String::from),
+            container: Some("mp4".into()),
+            ..Rendition::default()
+        })
+        .collect();
+
+    Ok(PlaybackInfo {
+        name: string("name"),
+        description: string("long_description").or_else(|| string("description")),
+        published_at: string("published_at")
+            .filter(|at| upload_date(at).is_some())
+            .or_else(|| string("created_at")),
+        duration_ms: json.get_u64("duration"),
+        poster: string("poster"),
+        hls_url: hls_urls.first().map(|url| url.to_string()),
+        mp4_sources,
+        text_tracks: extract_text_tracks(&json),
+    })
+}
+
+/// Fetch fresh playback info, since the CDN urls in it expire, then the HLS stream and the metadata.
+/// The chosen renditions and the source inventory go into [VideoMeta]. None when `stop` said so.
+pub fn download_native(
+    downloader: &mut Downloader,
+    job: &DownloadJob,
+    policy: &QualityPolicy,
+    subtitles: SubtitleMode,
+    stop: &dyn Fn() -> bool,
+    log: &mut File,
+) -> SResult<Option<PathBuf>> {
+    let video_id = &job.video_id;
+    let playback = downloader.fetch_fresh(DownType::Playback, &job.bc_video_id)?;
+    let playback_path = job.video_root.join(PLAYBACK_NAME);
+    std::fs::write(&playback_path, &playback.body).map_err(SError::io(&playback_path))?;
+    let playback = extract_playback(playback.body)?;
+
+    let Some(hls_url) = &playback.hls_url else {
+        return Err(SError::hls("no HLS source in playback info"));
+    };
+    let master_url =
+        Url::parse(hls_url).map_err(|e| SError::hls(format!("bad master url {hls_url}: {e}")))?;
+    info!("native download of {video_id} from {master_url}");
+    let Some(hls) = download_hls(
+        downloader,
+        &job.video_root,
+        video_id,
+        &master_url,
+        policy,
+        stop,
+        log,
+    )?
+    else {
+        return Ok(None);
+    };
+
+    // after the media, so a failed download leaves only bookkeeping behind. Best effort, a
+    // missing poster or caption must not make a downloaded video look removed
+    let mut missing_assets = Vec::new();
+    let title = playback.name.as_deref().unwrap_or(&job.title);
+    let upload_date = playback.published_at.as_deref().and_then(upload_date);
+    if upload_date.is_none() {
+        warn!(
+            "{video_id} has no usable published_at or created_at: {:?}",
+            playback.published_at
+        );
+    }
+    let info = InfoJson {
+        id: &job.bc_video_id,
+        title,
+        fulltitle: title,
+        upload_date,
+        description: playback.description.as_deref(),
+        duration: playback.duration_ms.map(|ms| ms as f64 / 1000.0),
+        extractor: "brightcove-native",
+    };
+    let info_path = job.video_root.join(format!("{video_id}.info.json"));
+    let saved_info = simd_json::to_vec_pretty(&info)
+        .map_err(SError::json(&info_path))
+        .and_then(|info_raw| std::fs::write(&info_path, info_raw).map_err(SError::io(&info_path)));
+    if let Err(e) = saved_info {
+        warn!("{video_id} info.json not saved: {e}");
+        missing_assets.push(format!("info.json: {e}"));
+    }
+
+    if let Some(poster) = &playback.poster {
+        let poster_path = job.video_root.join(format!("{video_id}.jpg"));
+        let saved_poster = downloader.fetch_url(poster).and_then(|poster_raw| {
+            std::fs::write(&poster_path, poster_raw).map_err(SError::io(&poster_path))
+        });
+        if let Err(e) = saved_poster {
+            warn!("{video_id} poster not saved: {e}");
+            missing_assets.push(format!("poster: {e}"));
+        }
+    }
+
+    let mut inventory = hls.inventory;
+    inventory.extend(playback.mp4_sources);
+    VideoMeta::record_renditions(video_id, policy.describe(), hls.chosen, inventory)?;
+
+    if subtitles != SubtitleMode::None {
+        let tracks = download_text_tracks(
+            downloader,
+            &job.video_root,
+            video_id,
+            &playback.text_tracks,
+            &mut missing_assets,
+        );
+        record_text_tracks(video_id, subtitles, tracks)?;
+    }
+    VideoMeta::record_missing_assets(video_id, missing_assets)?;
+
+    Ok(Some(hls.media_path))
+}
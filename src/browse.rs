@@ -0,0 +1,552 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::brightcove::PLAYBACK_NAME;
+use crate::crawl::{CrawlResult, NodeKey};
+use crate::downloader::{
+    BROWSE_COLLECTIONS_NAME, BROWSE_MONTHS_NAME, BROWSE_NAME, BROWSE_SHOWS_NAME, VIDEO_DL_NAME,
+    db_path, path,
+};
+use crate::err::{SError, SResult};
+use crate::extractor::ThingType;
+use crate::hierarchy::Hierarchy;
+use crate::naming::{Aired, NameFields, NamingTemplate};
+use crate::probe::expected_duration;
+use crate::sanitize::{Sanitizer, short_id};
+use crate::video_dl::list_video_dir;
+use chrono::{DateTime, NaiveDate, Utc};
+use simd_json::BorrowedValue;
+use simd_json::prelude::ValueObjectAccessAsScalar;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, read_dir, read_link, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
+use tracing::{info, trace, warn};
+
+/// Flat by date, by show and season, by collection, and by year and month
+const BROWSE_LAYOUTS: [&str; 4] = [
+    BROWSE_NAME,
+    BROWSE_SHOWS_NAME,
+    BROWSE_COLLECTIONS_NAME,
+    BROWSE_MONTHS_NAME,
+];
+
+/// The naming template and timezone the links on disk were made with
+pub const BROWSE_SCHEME_NAME: &str = "browse-scheme.txt";
+
+/// Every browse symlink we want, by directory under the output root and unsanitized name.
+/// Built from the crawl graph and the hierarchy, then [Self::sync] touches only links that
+/// differ from what is on disk
+pub struct BrowseTree {
+    sanitizer: Sanitizer,
+    naming: NamingTemplate,
+    /// Directory, name, video id
+    entries: BTreeSet<(PathBuf, String, String)>,
+}
+
+impl BrowseTree {
+    pub fn new(sanitizer: Sanitizer, naming: NamingTemplate) -> Self {
+        Self {
+            sanitizer,
+            naming,
+            entries: BTreeSet::new(),
+        }
+    }
+
+    /// browse/, browse-shows/, browse-collections/, and browse-months/ links of one downloaded
+    /// video. Several collections give several links. All but browse-shows/ are named by the
+    /// template. Videos the crawl no longer has keep their name from the info.json
+    pub fn add_video(
+        &mut self,
+        backend: &DownloaderBackend,
+        crawl: &CrawlResult,
+        hierarchy: &Hierarchy,
+        video_id: &str,
+    ) -> SResult<()> {
+        let video_key = NodeKey {
+            thing_type: ThingType::Video,
+            id: video_id.to_string(),
+        };
+        let catalog_title = crawl.get(&video_key).map(|video| video.title());
+        let facts = video_facts(backend, video_id, catalog_title)?;
+        let episode_ref = hierarchy.locate(video_id);
+        let local_date = self.naming.local_date(&facts.aired);
+        let dated_name = self.naming.render(&NameFields {
+            aired: facts.aired,
+            title: &facts.title,
+            catalog_title: catalog_title.unwrap_or(&facts.title),
+            id: video_id,
+            show: episode_ref.as_ref().map(|e| e.show.title.as_str()),
+            season: episode_ref
+                .as_ref()
+                .and_then(|e| e.episode.season.or(e.season.number)),
+            episode: episode_ref.as_ref().and_then(|e| e.episode.number),
+            duration: facts.duration,
+        });
+        let upload_year = local_date.format("%Y").to_string();
+        let upload_month = local_date.format("%m").to_string();
+
+        self.insert(path([BROWSE_NAME]), &dated_name, video_id);
+        self.insert(
+            path([BROWSE_MONTHS_NAME, &upload_year, &upload_month]),
+            &dated_name,
+            video_id,
+        );
+        for edge in crawl.graph.collections_containing(video_id) {
+            let collection_name = match crawl.get(&edge.from) {
+                Some(collection) => collection.title().to_string(),
+                None => edge.from.id.clone(),
+            };
+            self.insert(
+                path([
+                    BROWSE_COLLECTIONS_NAME,
+                    &self.sanitizer.name(&collection_name),
+                ]),
+                &dated_name,
+                video_id,
+            );
+        }
+        if let Some(episode_ref) = episode_ref {
+            self.insert(
+                path([
+                    BROWSE_SHOWS_NAME,
+                    &self.sanitizer.name(&episode_ref.show.title),
+                    &self.sanitizer.name(&episode_ref.season.display_name()),
+                ]),
+                &episode_ref.episode.display_name(),
+                video_id,
+            );
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, dir: PathBuf, name: &str, video_id: &str) {
+        self.entries
+            .insert((dir, name.to_string(), video_id.to_string()));
+    }
+
+    /// Sanitized link paths. Every video of a name that collides, including case-only
+    /// collisions on case-insensitive profiles, gets its short id appended, so the result does
+    /// not depend on which video the crawl found first
+    fn links(&self) -> BTreeMap<PathBuf, String> {
+        // case-insensitive filesystems merge "News" and "NEWS", pick one spelling for both
+        let mut dir_spellings: BTreeMap<String, &PathBuf> = BTreeMap::new();
+        for (dir, _, _) in &self.entries {
+            let key = self.sanitizer.collision_key(&dir.to_string_lossy());
+            dir_spellings
+                .entry(key)
+                .and_modify(|spelling| *spelling = (*spelling).min(dir))
+                .or_insert(dir);
+        }
+
+        let mut groups: BTreeMap<(&PathBuf, String), BTreeMap<&str, &str>> = BTreeMap::new();
+        for (dir, name, video_id) in &self.entries {
+            let dir = dir_spellings[&self.sanitizer.collision_key(&dir.to_string_lossy())];
+            let key = self.sanitizer.collision_key(&self.sanitizer.name(name));
+            groups.entry((dir, key)).or_default().insert(video_id, name);
+        }
+
+        let mut links = BTreeMap::new();
+        for ((dir, _), videos) in groups {
+            if videos.len() == 1 {
+                let (video_id, name) = videos.into_iter().next().unwrap();
+                links.insert(dir.join(self.sanitizer.name(name)), video_id.to_string());
+                continue;
+            }
+            let short_ids: BTreeSet<&str> = videos.keys().map(|id| short_id(id)).collect();
+            let use_short = short_ids.len() == videos.len();
+            for (video_id, name) in videos {
+                trace!("browse name collision {name} for {video_id}");
+                let suffix_id = if use_short {
+                    short_id(video_id)
+                } else {
+                    video_id
+                };
+                let final_name = self
+                    .sanitizer
+                    .name_with_suffix(name, &format!(" [{suffix_id}]"));
+                links.insert(dir.join(final_name), video_id.to_string());
+            }
+        }
+        links
+    }
+
+    /// Create or fix the links, then remove symlinks of the layouts nobody wants anymore and
+    /// the directories that leaves empty. A link whose video is still on disk is only removed
+    /// when its layout links that video elsewhere, so taken down, corrupt, or re-downloading
+    /// videos keep theirs. A link to a video that is wanted in the same
+    /// directory under another name is moved there. Moving every link to a new template or
+    /// timezone only happens with `rename`
+    pub fn sync(&self, dry_run: bool, rename: bool) -> SResult<()> {
+        let scheme = self.naming.scheme();
+        let scheme_path = db_path([BROWSE_SCHEME_NAME]);
+        // trees from before templates
+        let recorded = if scheme_path.exists() {
+            std::fs::read_to_string(&scheme_path)
+                .map_err(SError::io(&scheme_path))?
+                .trim()
+                .to_string()
+        } else {
+            NamingTemplate::default().scheme()
+        };
+        if recorded != scheme && !rename {
+            return Err(SError::browse_renamed(recorded, scheme));
+        }
+
+        let links = self.links();
+        let mut existing = Vec::new();
+        for layout in BROWSE_LAYOUTS {
+            list_links(&db_path([layout]), &path([layout]), &mut existing)?;
+        }
+        let mut movable: BTreeMap<(&Path, &str), &Path> = BTreeMap::new();
+        for (link_path, video_id) in &existing {
+            if !links.contains_key(link_path) {
+                movable.insert((link_path.parent().unwrap(), video_id), link_path);
+            }
+        }
+
+        let mut moved = BTreeSet::new();
+        for (link_path, video_id) in &links {
+            let final_path = db_path([]).join(link_path);
+            let parent = final_path.parent().unwrap();
+            if !parent.exists() && !dry_run {
+                create_dir_all(parent).map_err(SError::io(parent))?;
+            }
+            if !final_path.is_symlink()
+                && let Some(old_path) =
+                    movable.remove(&(link_path.parent().unwrap(), video_id.as_str()))
+            {
+                moved.insert(old_path);
+                let old_final_path = db_path([]).join(old_path);
+                if dry_run {
+                    println!(
+                        "dry-run: would rename link {} to {}",
+                        old_final_path.display(),
+                        final_path.display()
+                    );
+                    continue;
+                }
+                info!(
+                    "renaming link {} to {}",
+                    old_final_path.display(),
+                    final_path.display()
+                );
+                std::fs::rename(&old_final_path, &final_path)
+                    .map_err(SError::io(&old_final_path))?;
+            }
+            // relative, so the tree can be moved as a whole
+            let mut target: PathBuf = link_path.parent().unwrap().iter().map(|_| "..").collect();
+            target.extend([VIDEO_DL_NAME, video_id]);
+            link_video(&final_path, &target, dry_run)?;
+        }
+
+        let linked_in_layout: BTreeSet<(&OsStr, &str)> = links
+            .iter()
+            .map(|(link_path, video_id)| (layout_of(link_path), video_id.as_str()))
+            .collect();
+        for (link_path, video_id) in &existing {
+            if links.contains_key(link_path) || moved.contains(link_path.as_path()) {
+                continue;
+            }
+            let stale_path = db_path([]).join(link_path);
+            // follows the link
+            if stale_path.exists()
+                && !linked_in_layout.contains(&(layout_of(link_path), video_id.as_str()))
+            {
+                trace!("keeping {} of an unlisted video", stale_path.display());
+                continue;
+            }
+            if dry_run {
+                println!("dry-run: would remove stale link {}", stale_path.display());
+            } else {
+                info!("removing stale link {}", stale_path.display());
+                remove_file(&stale_path).map_err(SError::io(&stale_path))?;
+            }
+        }
+        if !dry_run {
+            for layout in BROWSE_LAYOUTS {
+                remove_empty_dirs(&db_path([layout]))?;
+            }
+            if recorded != scheme {
+                info!("browse naming is now {scheme}");
+            }
+            std::fs::write(&scheme_path, format!("{scheme}\n"))
+                .map_err(SError::io(&scheme_path))?;
+        }
+        Ok(())
+    }
+}
+
+fn layout_of(link_path: &Path) -> &OsStr {
+    link_path.iter().next().unwrap()
+}
+
+/// What the name template needs besides the catalog
+struct VideoFacts {
+    aired: Aired,
+    title: String,
+    duration: Option<f64>,
+}
+
+/// Title and air date come from the info.json if the backend wrote one, otherwise from the crawl
+/// and the media file's modification time. The native backend's playback.json and youtube-dl's
+/// timestamp give the air time, upload_date only the UTC day
+fn video_facts(
+    backend: &DownloaderBackend,
+    video_id: &str,
+    catalog_title: Option<&str>,
+) -> SResult<VideoFacts> {
+    let video_root = db_path([VIDEO_DL_NAME, video_id]);
+    if !video_root.exists() {
+        panic!("missing video dl {video_id}")
+    }
+    let spec = backend.artifacts();
+    let child_names = list_video_dir(video_id)?;
+    let duration = expected_duration(&video_root, &child_names)?;
+
+    let published_at = if child_names.iter().any(|n| n == PLAYBACK_NAME) {
+        let playback_path = video_root.join(PLAYBACK_NAME);
+        let mut raw = std::fs::read(&playback_path).map_err(SError::io(&playback_path))?;
+        let json: BorrowedValue =
+            simd_json::to_borrowed_value(&mut raw).map_err(SError::json(&playback_path))?;
+        ["published_at", "created_at"]
+            .into_iter()
+            .filter_map(|key| json.get_str(key))
+            .find_map(|at| match DateTime::parse_from_rfc3339(at) {
+                Ok(at) => Some(at.to_utc()),
+                Err(e) => {
+                    warn!("{video_id} bad playback date {at}: {e}");
+                    None
+                }
+            })
+    } else {
+        None
+    };
+    let media_modified = || -> SResult<DateTime<Utc>> {
+        let Some(media_name) = spec.find(&child_names, ArtifactKind::Media) else {
+            panic!("missing media in {}", video_root.display())
+        };
+        let media_path = video_root.join(media_name);
+        let modified = std::fs::metadata(&media_path)
+            .and_then(|m| m.modified())
+            .map_err(SError::io(&media_path))?;
+        Ok(modified.into())
+    };
+
+    Ok(match spec.find(&child_names, ArtifactKind::InfoJson) {
+        Some(info_name) => {
+            let info_path = video_root.join(info_name);
+            let mut info_raw = std::fs::read(&info_path).map_err(SError::io(&info_path))?;
+            let info_json =
+                simd_json::to_borrowed_value(&mut info_raw).map_err(SError::json(&info_path))?;
+            let timestamp = info_json
+                .get_i64("timestamp")
+                .map(|t| DateTime::from_timestamp(t, 0).expect("timestamp"));
+            let upload_date = info_json.get_str("upload_date").map(|upload_date| {
+                NaiveDate::parse_from_str(upload_date, "%Y%m%d")
+                    .unwrap_or_else(|e| panic!("bad upload_date {upload_date}: {e}"))
+            });
+            let aired = match (published_at.or(timestamp), upload_date) {
+                (Some(at), _) => Aired::At(at),
+                (None, Some(day)) => Aired::Day(day),
+                // native downloads without any playback date
+                (None, None) => Aired::At(media_modified()?),
+            };
+            VideoFacts {
+                aired,
+                title: info_json
+                    .get_str("fulltitle")
+                    .expect("fulltitle")
+                    .to_string(),
+                duration,
+            }
+        }
+        None => VideoFacts {
+            aired: Aired::At(match published_at {
+                Some(at) => at,
+                None => media_modified()?,
+            }),
+            title: catalog_title.unwrap_or(video_id).to_string(),
+            duration,
+        },
+    })
+}
+
+/// Every symlink under `dir` with the video it points to. Anything that isn't a symlink is left
+/// alone
+fn list_links(dir: &Path, relative: &Path, links: &mut Vec<(PathBuf, String)>) -> SResult<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in read_dir(dir).map_err(SError::io(dir))? {
+        let entry = entry.map_err(SError::io(dir))?;
+        let entry_path = entry.path();
+        let entry_relative = relative.join(entry.file_name());
+        if entry_path.is_symlink() {
+            let target = read_link(&entry_path).map_err(SError::io(&entry_path))?;
+            let video_id = target
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            links.push((entry_relative, video_id));
+        } else if entry_path.is_dir() {
+            list_links(&entry_path, &entry_relative, links)?;
+        }
+    }
+    Ok(())
+}
+
+/// Below the layout root, which stays
+fn remove_empty_dirs(dir: &Path) -> SResult<()> {
+    for entry in read_dir(dir).map_err(SError::io(dir))? {
+        let entry_path = entry.map_err(SError::io(dir))?.path();
+        if entry_path.is_symlink() || !entry_path.is_dir() {
+            continue;
+        }
+        remove_empty_dirs(&entry_path)?;
+        let is_empty = read_dir(&entry_path)
+            .map_err(SError::io(&entry_path))?
+            .next()
+            .is_none();
+        if is_empty {
+            trace!("removing empty {}", entry_path.display());
+            remove_dir(&entry_path).map_err(SError::io(&entry_path))?;
+        }
+    }
+    Ok(())
+}
+
+fn link_video(final_path: &Path, target: &Path, dry_run: bool) -> SResult<()> {
+    let needs_create = if final_path.is_symlink() {
+        let existing = read_link(final_path).map_err(SError::io(final_path))?;
+        if existing == target {
+            trace!("skipping existing");
+            false
+        } else if dry_run {
+            println!(
+                "dry-run: would change link {} from {} to {}",
+                final_path.display(),
+                existing.display(),
+                target.display()
+            );
+            false
+        } else {
+            info!(
+                "relinking {} from {}",
+                final_path.display(),
+                existing.display()
+            );
+            remove_file(final_path).map_err(SError::io(final_path))?;
+            true
+        }
+    } else if final_path.exists() {
+        panic!("unknown existing {}", final_path.display());
+    } else if dry_run {
+        println!(
+            "dry-run: would link {} to {}",
+            final_path.display(),
+            target.display()
+        );
+        false
+    } else {
+        true
+    };
+
+    if needs_create {
+        info!("linking {} to {}", final_path.display(), target.display());
+        std::os::unix::fs::symlink(target, final_path).map_err(SError::io(final_path))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::SanitizeProfile;
+
+    fn tree(profile: SanitizeProfile) -> BrowseTree {
+        let sanitizer = Sanitizer {
+            profile,
+            max_bytes: 255,
+        };
+        BrowseTree::new(sanitizer, NamingTemplate::default())
+    }
+
+    fn names(links: &BTreeMap<PathBuf, String>) -> Vec<(String, &str)> {
+        links
+            .iter()
+            .map(|(path, video_id)| (path.to_string_lossy().into_owned(), video_id.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn unique_names_keep_no_suffix() {
+        let mut tree = tree(SanitizeProfile::Posix);
+        tree.insert(
+            PathBuf::from("browse"),
+            "2024-01-01 News: Late",
+            "6300000000001",
+        );
+        assert_eq!(
+            names(&tree.links()),
+            [("browse/2024-01-01 News - Late".into(), "6300000000001")]
+        );
+    }
+
+    #[test]
+    fn colliding_names_get_short_ids() {
+        let mut tree = tree(SanitizeProfile::Posix);
+        tree.insert(PathBuf::from("browse"), "2024-01-01 News", "6300000000002");
+        tree.insert(PathBuf::from("browse"), "2024-01-01 News", "6300000000001");
+        tree.insert(
+            PathBuf::from("browse-shows"),
+            "2024-01-01 News",
+            "6300000000003",
+        );
+        assert_eq!(
+            names(&tree.links()),
+            [
+                ("browse/2024-01-01 News [00000001]".into(), "6300000000001"),
+                ("browse/2024-01-01 News [00000002]".into(), "6300000000002"),
+                ("browse-shows/2024-01-01 News".into(), "6300000000003"),
+            ]
+        );
+    }
+
+    #[test]
+    fn colliding_short_ids_fall_back_to_full_ids() {
+        let mut tree = tree(SanitizeProfile::Posix);
+        tree.insert(PathBuf::from("browse"), "News", "1100000001");
+        tree.insert(PathBuf::from("browse"), "News", "2200000001");
+        assert_eq!(
+            names(&tree.links()),
+            [
+                ("browse/News [1100000001]".into(), "1100000001"),
+                ("browse/News [2200000001]".into(), "2200000001"),
+            ]
+        );
+    }
+
+    #[test]
+    fn case_only_collisions_on_case_insensitive_profiles() {
+        let mut posix = tree(SanitizeProfile::Posix);
+        let mut exfat = tree(SanitizeProfile::Exfat);
+        for tree in [&mut posix, &mut exfat] {
+            tree.insert(PathBuf::from("browse"), "News", "6300000000001");
+            tree.insert(PathBuf::from("browse"), "NEWS", "6300000000002");
+        }
+        assert_eq!(
+            names(&posix.links()),
+            [
+                ("browse/NEWS".into(), "6300000000002"),
+                ("browse/News".into(), "6300000000001"),
+            ]
+        );
+        assert_eq!(
+            names(&exfat.links()),
+            [
+                ("browse/NEWS [00000002]".into(), "6300000000002"),
+                ("browse/News [00000001]".into(), "6300000000001"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::backend::DownloaderBackend;
+use crate::crawl::{CrawlNode, CrawlResult, NodeKey, SNAPSHOT_TIME_FORMAT};
+use crate::downloader::{CHANGELOG_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::extractor::ThingType;
+use crate::video_dl::{DownloadStates, VideoState};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use tracing::info;
+
+/// What the site did between two crawl snapshots
+#[derive(Serialize)]
+pub struct Changelog {
+    pub previous: String,
+    pub current: String,
+    pub added: Vec<VideoChange>,
+    pub removed: Vec<VideoChange>,
+    pub changed: Vec<MetaChange>,
+    pub membership: Vec<MembershipChange>,
+}
+
+#[derive(Serialize)]
+pub struct VideoChange {
+    pub id: String,
+    pub title: String,
+    /// Gone from the site but we still have it in vid-dl
+    pub archive_only: bool,
+}
+
+#[derive(Serialize)]
+pub struct MetaChange {
+    #[serde(flatten)]
+    pub key: NodeKey,
+    pub field: &'static str,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MembershipChange {
+    pub video_id: String,
+    pub collection_id: String,
+    /// None when newly added to the collection
+    pub before_position: Option<usize>,
+    /// None when removed from the collection
+    pub after_position: Option<usize>,
+}
+
+impl Changelog {
+    /// Archive-only flags come from the download states, only complete downloads count
+    pub fn compare(
+        previous: &CrawlResult,
+        current: &CrawlResult,
+        backend: &DownloaderBackend,
+        states: &DownloadStates,
+    ) -> SResult<Self> {
+        let prev_nodes = index_nodes(previous);
+        let cur_nodes = index_nodes(current);
+
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for (key, cur) in &cur_nodes {
+            let Some(prev) = prev_nodes.get(key) else {
+                if key.thing_type == ThingType::Video {
+                    added.push(video_change(cur, backend, states)?);
+                }
+                continue;
+            };
+            let fields = [
+                ("title", &prev.meta.title, &cur.meta.title),
+                ("description", &prev.meta.description, &cur.meta.description),
+                ("artwork", &prev.meta.artwork, &cur.meta.artwork),
+            ];
+            for (field, before, after) in fields {
+                if before != after {
+                    changed.push(MetaChange {
+                        key: (*key).clone(),
+                        field,
+                        before: before.clone(),
+                        after: after.clone(),
+                    });
+                }
+            }
+        }
+        let removed = prev_nodes
+            .iter()
+            .filter(|(key, _)| key.thing_type == ThingType::Video && !cur_nodes.contains_key(key))
+            .map(|(_, prev)| video_change(prev, backend, states))
+            .try_collect()?;
+
+        let prev_members = index_membership(previous);
+        let cur_members = index_membership(current);
+        let member_keys: BTreeSet<&(String, String)> =
+            prev_members.keys().chain(cur_members.keys()).collect();
+        let mut membership = Vec::new();
+        for member_key in member_keys {
+            let before_position = prev_members.get(member_key).copied();
+            let after_position = cur_members.get(member_key).copied();
+            if before_position != after_position {
+                let (video_id, collection_id) = member_key;
+                membership.push(MembershipChange {
+                    video_id: video_id.clone(),
+                    collection_id: collection_id.clone(),
+                    before_position,
+                    after_position,
+                });
+            }
+        }
+
+        Ok(Changelog {
+            previous: previous.finished.format(SNAPSHOT_TIME_FORMAT).to_string(),
+            current: current.finished.format(SNAPSHOT_TIME_FORMAT).to_string(),
+            added,
+            removed,
+            changed,
+            membership,
+        })
+    }
+
+    pub fn write(&self) -> SResult<()> {
+        let name = format!("{}_{}", self.previous, self.current);
+        let json_path = db_path([CHANGELOG_NAME, &format!("{name}.json")]);
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&json_path))?;
+        std::fs::write(&json_path, raw).map_err(SError::io(&json_path))?;
+
+        let md_path = db_path([CHANGELOG_NAME, &format!("{name}.md")]);
+        std::fs::write(&md_path, self.to_markdown()).map_err(SError::io(&md_path))?;
+
+        info!(
+            "changelog {} added {} removed {} changed {} membership to {}",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.membership.len(),
+            md_path.display()
+        );
+        Ok(())
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!("# Catalog changes {} to {}\n", self.previous, self.current);
+
+        writeln!(md, "\n## Added videos ({})\n", self.added.len()).unwrap();
+        for video in &self.added {
+            writeln!(md, "- `{}` {}", video.id, video.title).unwrap();
+        }
+
+        writeln!(md, "\n## Removed videos ({})\n", self.removed.len()).unwrap();
+        for video in &self.removed {
+            let flag = if video.archive_only {
+                " **archive-only**"
+            } else {
+                ""
+            };
+            writeln!(md, "- `{}` {}{flag}", video.id, video.title).unwrap();
+        }
+
+        writeln!(md, "\n## Metadata changes ({})\n", self.changed.len()).unwrap();
+        for change in &self.changed {
+            writeln!(
+                md,
+                "- {:?} `{}` {}: {} -> {}",
+                change.key.thing_type,
+                change.key.id,
+                change.field,
+                md_value(&change.before),
+                md_value(&change.after)
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            md,
+            "\n## Collection membership ({})\n",
+            self.membership.len()
+        )
+        .unwrap();
+        for change in &self.membership {
+            let what = match (change.before_position, change.after_position) {
+                (None, Some(after)) => format!("added at position {after}"),
+                (Some(before), None) => format!("removed from position {before}"),
+                (Some(before), Some(after)) => format!("moved from position {before} to {after}"),
+                (None, None) => unreachable!(),
+            };
+            writeln!(
+                md,
+                "- video `{}` in collection `{}` {what}",
+                change.video_id, change.collection_id
+            )
+            .unwrap();
+        }
+        md
+    }
+}
+
+fn index_nodes(crawl: &CrawlResult) -> BTreeMap<NodeKey, &CrawlNode> {
+    crawl.nodes.iter().map(|node| (node.key(), node)).collect()
+}
+
+/// (video, collection) -> position
+fn index_membership(crawl: &CrawlResult) -> BTreeMap<(String, String), usize> {
+    crawl
+        .graph
+        .edges
+        .iter()
+        .filter(|e| {
+            e.from.thing_type == ThingType::Collection && e.to.thing_type == ThingType::Video
+        })
+        .map(|e| ((e.to.id.clone(), e.from.id.clone()), e.position))
+        .collect()
+}
+
+fn video_change(
+    node: &CrawlNode,
+    backend: &DownloaderBackend,
+    states: &DownloadStates,
+) -> SResult<VideoChange> {
+    Ok(VideoChange {
+        id: node.id.clone(),
+        title: node.title().into(),
+        archive_only: states.state_of(backend, &node.id)? == VideoState::Complete,
+    })
+}
+
+fn md_value(value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\n', " ")),
+        None => "(none)".into(),
+    }
+}
@@ -0,0 +1,180 @@
+use crate::downloader::EXTRACTION_DB_ROOT;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Archival of a certain fun entertainment eagle-powered video site"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Args)]
+pub struct GlobalArgs {
+    /// key=value file with DOMAIN, BC_ACCOUNT_ID, and missing= lines
+    #[arg(long, global = true, default_value = ".env")]
+    pub config: PathBuf,
+
+    /// Where caches, vid-dl, browse, and reports live
+    #[arg(long, global = true, default_value = EXTRACTION_DB_ROOT)]
+    pub output_root: PathBuf,
+
+    /// Only use cached responses, fail on anything that needs the network
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Report what would be fetched, downloaded, and linked without writing anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Compact)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Spider the catalog, save a snapshot, write the changelog, graph, and hierarchy
+    Crawl {
+        /// Continue from the saved crawl state instead of starting over
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Write ytdl-scrape.sh for videos of the latest crawl not yet downloaded
+    PlanDownloads,
+    /// Run the downloader for every video of the latest crawl not yet downloaded
+    Download {
+        #[command(flatten)]
+        download: DownloadArgs,
+    },
+    /// Fetch fresh metadata of downloaded videos and download edited ones again as dated versions
+    Recheck {
+        /// Skip videos checked or downloaded more recently than this
+        #[arg(long, default_value_t = 30)]
+        min_age_days: u64,
+        /// Check at most this many videos, oldest check first
+        #[arg(long)]
+        limit: Option<usize>,
+        #[command(flatten)]
+        download: DownloadArgs,
+    },
+    /// Where two versions of a video differ, as JSON and an edit decision list
+    DiffVersions {
+        /// A directory under vid-dl like `<id>@<date>`, or an HLS playlist url
+        old: String,
+        new: String,
+    },
+    /// Watch the live_collection= rails and record streams until they end, until Ctrl-C
+    Live {
+        /// Pause between looking for new live streams
+        #[arg(long, default_value_t = 300)]
+        poll_secs: u64,
+    },
+    /// Link downloaded videos into the browse layouts, removing links nothing points to anymore
+    Browse {
+        /// Move the existing links to a changed BROWSE_TEMPLATE= or BROWSE_TIMEZONE=
+        #[arg(long)]
+        rename: bool,
+    },
+    /// Check every video directory of the latest crawl is complete
+    Verify {
+        /// Also check the media with ffprobe and flag broken videos for re-download
+        #[arg(long)]
+        probe: bool,
+        /// Allowed difference to the catalog duration, at least 1% of it
+        #[arg(long, default_value_t = 2.0)]
+        duration_tolerance_secs: f64,
+    },
+    /// Record SHA-256 of new and rewritten files under vid-dl and the response caches
+    Checksum {
+        /// Drop records of files that are gone, e.g. after a re-download
+        #[arg(long)]
+        forget_missing: bool,
+    },
+    /// Hash the archive again, report bit rot, missing, and unexpected files
+    Audit,
+    /// Package videos as a BagIt bag with catalog metadata in bag-info.txt
+    ExportBag {
+        /// New directory for the bag
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(required = true)]
+        video_ids: Vec<String>,
+    },
+    /// Metadata history of one video, or every change captured in a time window
+    History {
+        video_id: Option<String>,
+        /// First day of the window
+        #[arg(long, conflicts_with = "video_id")]
+        since: Option<NaiveDate>,
+        /// Day after the window
+        #[arg(long, conflicts_with = "video_id")]
+        until: Option<NaiveDate>,
+        /// Import every crawl snapshot and info.json first, for archives older than the history
+        #[arg(long)]
+        backfill: bool,
+    },
+    /// Summary of the crawl and download progress
+    Status,
+    /// Report failed and unavailable videos with the classified reason
+    Failures,
+    /// Set the download state of a video by hand
+    Mark {
+        video_id: String,
+        #[arg(value_enum)]
+        state: MarkState,
+        /// Kept in the state record
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Compare two crawl snapshots, the latest two by default
+    Changelog {
+        #[arg(long)]
+        previous: Option<PathBuf>,
+        #[arg(long)]
+        current: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+pub struct DownloadArgs {
+    /// Downloads running at the same time
+    #[arg(long, default_value_t = 1)]
+    pub concurrency: usize,
+    /// Kill a single download after this long
+    #[arg(long, default_value_t = 180)]
+    pub timeout_mins: u64,
+    /// Pause between downloads, per worker
+    #[arg(long, default_value_t = 20)]
+    pub delay_secs: u64,
+    /// Extra attempts for network and unknown failures
+    #[arg(long, default_value_t = 2)]
+    pub retries: u32,
+    /// Wait before the first retry, doubled after each
+    #[arg(long, default_value_t = 60)]
+    pub retry_backoff_secs: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum MarkState {
+    /// Skip until reset, e.g. while looking into a broken download
+    Quarantined,
+    /// Gone for good
+    Unavailable,
+    /// Forget the recorded state, the directory decides again
+    Reset,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Compact,
+    Pretty,
+    Full,
+    Json,
+}
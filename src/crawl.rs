@@ -0,0 +1,296 @@
+use crate::download::{install_shutdown_handler, is_shutdown};
+use crate::downloader::{CRAWL_STATE_NAME, CRAWLS_NAME, DownType, Downloader, db_path};
+use crate::err::{SError, SResult};
+use crate::extractor::{
+    ExtractedListing, ExtractedThing, ThingMeta, ThingType, extract_collections_from_root,
+    extract_original_id, extract_things_from_collection,
+};
+use crate::graph::CrawlGraph;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+pub const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// The whole state is rewritten, so not after every page. Ctrl-C and the end still save
+const CRAWL_STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct NodeKey {
+    pub thing_type: ThingType,
+    pub id: String,
+}
+
+/// A visited page, collection, or video with the best metadata we found for it
+#[derive(Serialize, Deserialize)]
+pub struct CrawlNode {
+    pub thing_type: ThingType,
+    pub id: String,
+    pub meta: ThingMeta,
+}
+
+impl CrawlNode {
+    pub fn key(&self) -> NodeKey {
+        NodeKey {
+            thing_type: self.thing_type,
+            id: self.id.clone(),
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        self.meta.display_title(&self.id)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CrawlResult {
+    pub finished: DateTime<Utc>,
+    /// In visit order, root page first
+    pub nodes: Vec<CrawlNode>,
+    pub graph: CrawlGraph,
+    /// Position in [Self::nodes], built on first lookup
+    #[serde(skip)]
+    index: OnceLock<HashMap<NodeKey, usize>>,
+}
+
+impl CrawlResult {
+    pub fn root(&self) -> &CrawlNode {
+        &self.nodes[0]
+    }
+
+    pub fn get(&self, key: &NodeKey) -> Option<&CrawlNode> {
+        let index = self.index.get_or_init(|| {
+            self.nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| (node.key(), i))
+                .collect()
+        });
+        index.get(key).map(|i| &self.nodes[*i])
+    }
+
+    pub fn videos(&self) -> impl Iterator<Item = &CrawlNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.thing_type == ThingType::Video)
+    }
+
+    /// Keep every finished crawl so later runs can diff against it
+    pub fn save_snapshot(&self) -> SResult<PathBuf> {
+        let name = format!("{}.json", self.finished.format(SNAPSHOT_TIME_FORMAT));
+        let snapshot_path = db_path([CRAWLS_NAME, &name]);
+        let raw = simd_json::to_vec(self).map_err(SError::json(&snapshot_path))?;
+        std::fs::write(&snapshot_path, raw).map_err(SError::io(&snapshot_path))?;
+        info!("saved crawl snapshot {}", snapshot_path.display());
+        Ok(snapshot_path)
+    }
+
+    pub fn load_snapshot(snapshot_path: &Path) -> SResult<Self> {
+        let mut raw = std::fs::read(snapshot_path).map_err(SError::io(snapshot_path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(snapshot_path))
+    }
+
+    pub fn load_latest() -> SResult<Self> {
+        match Self::list_snapshots()?.last() {
+            Some(latest) => Self::load_snapshot(latest),
+            None => Err(SError::no_snapshot()),
+        }
+    }
+
+    /// Oldest first. The names sort by time
+    pub fn list_snapshots() -> SResult<Vec<PathBuf>> {
+        let crawls_root = db_path([CRAWLS_NAME]);
+        let mut snapshots: Vec<PathBuf> = read_dir(&crawls_root)
+            .map_err(SError::io(&crawls_root))?
+            .map(|e| e.map(|e| e.path()))
+            .try_collect()
+            .map_err(SError::io(&crawls_root))?;
+        snapshots.retain(|p| p.extension().is_some_and(|ext| ext == "json"));
+        snapshots.sort();
+        Ok(snapshots)
+    }
+}
+
+/// Everything needed to pick a crawl back up after a crash or Ctrl-C
+#[derive(Default, Serialize, Deserialize)]
+struct CrawlState {
+    frontier: Vec<ExtractedThing>,
+    visited: HashSet<NodeKey>,
+    nodes: Vec<CrawlNode>,
+    /// Missing in state files from before the graph
+    #[serde(default)]
+    graph: CrawlGraph,
+}
+
+impl CrawlState {
+    fn load() -> SResult<Option<Self>> {
+        let state_path = db_path([CRAWL_STATE_NAME]);
+        if !state_path.exists() {
+            return Ok(None);
+        }
+        let mut raw = std::fs::read(&state_path).map_err(SError::io(&state_path))?;
+        let state = simd_json::from_slice(&mut raw).map_err(SError::json(&state_path))?;
+        Ok(Some(state))
+    }
+
+    /// Write then rename so a kill mid-write keeps the previous state
+    fn save(&self) -> SResult<()> {
+        let state_path = db_path([CRAWL_STATE_NAME]);
+        let temp_path = state_path.with_extension("json.tmp");
+        let raw = simd_json::to_vec(self).map_err(SError::json(&state_path))?;
+        std::fs::write(&temp_path, raw).map_err(SError::io(&temp_path))?;
+        std::fs::rename(&temp_path, &state_path).map_err(SError::io(&state_path))
+    }
+}
+
+/// Once the snapshot is saved, so a later --resume starts a fresh crawl instead of saving the
+/// finished one again
+pub fn clear_crawl_state() -> SResult<()> {
+    let state_path = db_path([CRAWL_STATE_NAME]);
+    if state_path.exists() {
+        std::fs::remove_file(&state_path).map_err(SError::io(&state_path))?;
+    }
+    Ok(())
+}
+
+/// Queue size of an unfinished crawl, if there is saved state at all
+pub fn pending_frontier() -> SResult<Option<usize>> {
+    Ok(CrawlState::load()?.map(|state| state.frontier.len()))
+}
+
+/// In dry-run mode nothing is saved and uncached pages and collections are left unexpanded
+pub fn crawl_catalog(
+    downloader: &mut Downloader,
+    resume: bool,
+    dry_run: bool,
+) -> SResult<CrawlResult> {
+    let saved_state = if resume { CrawlState::load()? } else { None };
+    let mut state = match saved_state {
+        Some(state) => {
+            info!(
+                "resuming crawl with {} queued and {} visited",
+                state.frontier.len(),
+                state.visited.len()
+            );
+            state
+        }
+        None => {
+            if resume {
+                warn!("no crawl state to resume, starting over");
+            }
+            let mut state = CrawlState::default();
+            if let Some(root_id) = load_root_collection_id(downloader)? {
+                state.frontier.push(ExtractedThing {
+                    next_type: ThingType::Page,
+                    next_id: root_id,
+                    meta: ThingMeta::default(),
+                });
+            }
+            state
+        }
+    };
+
+    install_shutdown_handler();
+    let mut last_save = Instant::now();
+    while let Some(cur_thing) = state.frontier.pop() {
+        if is_shutdown() {
+            if !dry_run {
+                state.frontier.push(cur_thing);
+                state.save()?;
+            }
+            return Err(SError::crawl_interrupted());
+        }
+        if !state.visited.insert(cur_thing.key()) {
+            // Apparently videos exist in multiple collections
+            assert_eq!(cur_thing.next_type, ThingType::Video);
+
+            debug!(
+                "skipping seen id {}, membership is in the graph",
+                cur_thing.next_id
+            );
+            continue;
+        }
+
+        let listing = match cur_thing.next_type {
+            ThingType::Page => load_collections_from_page(downloader, &cur_thing.next_id)?,
+            ThingType::Collection => load_collection(downloader, &cur_thing.next_id)?,
+            ThingType::Video => None,
+        };
+        let mut node = CrawlNode {
+            thing_type: cur_thing.next_type,
+            id: cur_thing.next_id,
+            meta: cur_thing.meta,
+        };
+        let fetched = listing.is_some();
+        if let Some(listing) = listing {
+            expand(&mut state, &mut node, listing);
+        }
+        state.nodes.push(node);
+        // videos are free to redo, only checkpoint after real work
+        if fetched && !dry_run && last_save.elapsed() >= CRAWL_STATE_SAVE_INTERVAL {
+            state.save()?;
+            last_save = Instant::now();
+        }
+    }
+    if !dry_run {
+        state.save()?;
+    }
+
+    let result = CrawlResult {
+        finished: Utc::now(),
+        nodes: state.nodes,
+        graph: state.graph,
+        index: OnceLock::new(),
+    };
+    let shared_videos = result
+        .videos()
+        .filter(|video| result.graph.collections_containing(&video.id).len() > 1)
+        .count();
+    info!(
+        "extracted {} videos from {} nodes, {shared_videos} in multiple collections",
+        result.videos().count(),
+        result.nodes.len()
+    );
+    Ok(result)
+}
+
+/// Queue the children and take the node's own metadata.
+/// What the thing says about itself wins over what its parent listed it as.
+fn expand(state: &mut CrawlState, node: &mut CrawlNode, listing: ExtractedListing) {
+    node.meta = listing.meta.or(std::mem::take(&mut node.meta));
+    state
+        .graph
+        .record(&node.key(), listing.things.iter().map(ExtractedThing::key));
+    state.frontier.extend(listing.things);
+}
+
+fn load_root_collection_id(downloader: &mut Downloader) -> SResult<Option<String>> {
+    match downloader.fetch_or_plan(DownType::Html, "")? {
+        Some(content) => extract_original_id(&content.body).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn load_collections_from_page(
+    downloader: &mut Downloader,
+    root_id: &str,
+) -> SResult<Option<ExtractedListing>> {
+    match downloader.fetch_or_plan(DownType::Page, root_id)? {
+        Some(content) => extract_collections_from_root(content.body).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn load_collection(
+    downloader: &mut Downloader,
+    collection_id: &str,
+) -> SResult<Option<ExtractedListing>> {
+    match downloader.fetch_or_plan(DownType::Collection, collection_id)? {
+        Some(content) => extract_things_from_collection(content.body).map(Some),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,507 @@
+use crate::backend::DownloaderBackend;
+use crate::brightcove::download_native;
+use crate::downloader::{Downloader, db_path};
+use crate::err::{SError, SResult};
+use crate::failure::{Classified, FailureClass, classify_failure};
+use crate::global_config::GlobalConfig;
+use crate::meta_history::{MetadataHistory, info_json_entries};
+use crate::quality::QualityPolicy;
+use crate::subtitles::{SubtitleMode, find_text_tracks, record_text_tracks};
+use crate::video_dl::{
+    DONE_MARKER_NAME, DownloadJob, DownloadStates, VideoState, YTDL_LOG_NAME, inspect_video_dir,
+};
+use crate::video_meta::record_ytdl_renditions;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+pub const DOWNLOAD_ATTEMPTS_NAME: &str = "download-attempts.jsonl";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Doubling stops here, however many retries were asked for
+const MAX_RETRY_BACKOFF: Duration = Duration::from_hours(6);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_HANDLER: Once = Once::new();
+
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub concurrency: usize,
+    /// Kill a download taking longer than this
+    pub timeout: Duration,
+    /// Per worker pause between downloads. Be a nice scraper
+    pub delay: Duration,
+    /// Extra attempts for transient failures
+    pub retries: u32,
+    /// Doubled after every retry, up to [MAX_RETRY_BACKOFF]
+    pub retry_backoff: Duration,
+}
+
+/// Wait before retrying after the given attempt, the first retry waits `backoff`
+fn retry_backoff(backoff: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt.saturating_sub(1))
+        .and_then(|factor| backoff.checked_mul(factor))
+        .map_or(MAX_RETRY_BACKOFF, |wait| wait.min(MAX_RETRY_BACKOFF))
+}
+
+#[derive(Serialize)]
+pub struct DownloadAttempt {
+    pub video_id: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// None when killed or never started
+    pub exit_code: Option<i32>,
+    pub outcome: AttemptOutcome,
+    /// As reported in the downloader's log
+    pub media_file: Option<String>,
+    /// 1 for the first try
+    pub attempt: u32,
+    pub failure: Option<FailureClass>,
+    /// The log line the failure class came from
+    pub failure_evidence: Option<String>,
+    /// Requeued with this backoff
+    pub retry_in_secs: Option<u64>,
+}
+
+struct QueuedJob {
+    job: DownloadJob,
+    attempt: u32,
+    not_before: Instant,
+}
+
+/// Retries go back in, so workers only stop once nothing is waiting or running
+#[derive(Default)]
+struct JobQueue {
+    waiting: VecDeque<QueuedJob>,
+    in_flight: usize,
+}
+
+enum NextJob {
+    Ready(QueuedJob),
+    /// Only backed off retries left, or others still running that may requeue
+    Wait,
+    Done,
+}
+
+impl JobQueue {
+    fn next(&mut self) -> NextJob {
+        let now = Instant::now();
+        if let Some(pos) = self.waiting.iter().position(|q| q.not_before <= now) {
+            self.in_flight += 1;
+            NextJob::Ready(self.waiting.remove(pos).unwrap())
+        } else if self.waiting.is_empty() && self.in_flight == 0 {
+            NextJob::Done
+        } else {
+            NextJob::Wait
+        }
+    }
+}
+
+/// Hands the job back to [JobQueue::in_flight] however the attempt ends, a panicked worker
+/// would otherwise leave the others waiting forever
+struct InFlight<'q>(&'q Mutex<JobQueue>);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut queue = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.in_flight -= 1;
+    }
+}
+
+enum WorkerEvent {
+    Started(String),
+    Finished(DownloadAttempt),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum AttemptOutcome {
+    Success,
+    Failed,
+    TimedOut,
+    Interrupted,
+}
+
+/// First Ctrl-C stops new downloads and kills running ones, second exits immediately
+pub fn install_shutdown_handler() {
+    SHUTDOWN_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            if SHUTDOWN.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            warn!("Ctrl-C, stopping downloads, recordings, and crawls. Again to exit now");
+        })
+        .expect("ctrl-c handler");
+    });
+}
+
+pub fn is_shutdown() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Run the downloader for every job, append each attempt to [DOWNLOAD_ATTEMPTS_NAME],
+/// and keep the per video state up to date. Only this thread writes the state
+pub fn run_downloads(
+    global_config: &GlobalConfig,
+    states: &mut DownloadStates,
+    jobs: Vec<DownloadJob>,
+    options: &DownloadOptions,
+) -> SResult<Vec<DownloadAttempt>> {
+    install_shutdown_handler();
+    let backend = &global_config.downloader;
+    let total = jobs.len();
+    let now = Instant::now();
+    let queue = Arc::new(Mutex::new(JobQueue {
+        waiting: jobs
+            .into_iter()
+            .map(|job| QueuedJob {
+                job,
+                attempt: 1,
+                not_before: now,
+            })
+            .collect(),
+        in_flight: 0,
+    }));
+    let (sender, receiver) = channel();
+
+    // native workers share one throttle, more workers must not mean more requests per second
+    let downloader = matches!(backend, DownloaderBackend::Native)
+        .then(|| Downloader::init(global_config, false, false));
+    let workers: Vec<_> = (0..options.concurrency.max(1))
+        .map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            let backend = backend.clone();
+            let quality = global_config.quality.clone();
+            let subtitles = global_config.subtitles;
+            let options = options.clone();
+            let downloader = downloader.clone();
+            thread::spawn(move || {
+                download_worker(
+                    &backend, &quality, subtitles, downloader, &queue, &sender, &options,
+                )
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let attempts_path = db_path([DOWNLOAD_ATTEMPTS_NAME]);
+    let mut attempts_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&attempts_path)
+        .map_err(SError::io(&attempts_path))?;
+    let mut history = MetadataHistory::load()?;
+    let mut attempts = Vec::new();
+    // jobs that won't be retried in this run
+    let mut settled = 0;
+    for event in receiver {
+        let attempt = match event {
+            WorkerEvent::Started(video_id) => {
+                states.set(&video_id, VideoState::InProgress);
+                states.save()?;
+                continue;
+            }
+            WorkerEvent::Finished(attempt) => attempt,
+        };
+        if attempt.outcome == AttemptOutcome::Success
+            && let Err(e) = info_json_entries(backend, &attempt.video_id)
+                .and_then(|entries| history.record(entries, false))
+        {
+            warn!("metadata history of {} not recorded: {e}", attempt.video_id);
+        }
+        // the native downloader records its own
+        if attempt.outcome == AttemptOutcome::Success
+            && !matches!(backend, DownloaderBackend::Native)
+            && let Err(e) = record_ytdl_meta(global_config, &attempt.video_id)
+        {
+            warn!("metadata of {} not recorded: {e}", attempt.video_id);
+        }
+        states.set(&attempt.video_id, attempt_state(backend, &attempt)?);
+        states.record_failure(&attempt.video_id, attempt.failure);
+        states.save()?;
+        if attempt.retry_in_secs.is_none() {
+            settled += 1;
+        }
+        info!(
+            "[{settled}/{total}] {} {:?} exit {:?} attempt {}{}",
+            attempt.video_id,
+            attempt.outcome,
+            attempt.exit_code,
+            attempt.attempt,
+            match (attempt.failure, attempt.retry_in_secs) {
+                (Some(class), Some(secs)) => format!(" {}, retry in {secs}s", class.as_ref()),
+                (Some(class), None) => format!(" {}, giving up", class.as_ref()),
+                _ => String::new(),
+            }
+        );
+        let mut line = simd_json::to_vec(&attempt).map_err(SError::json(&attempts_path))?;
+        line.push(b'\n');
+        attempts_file
+            .write_all(&line)
+            .map_err(SError::io(&attempts_path))?;
+        attempts.push(attempt);
+    }
+    for worker in workers {
+        worker.join().expect("download worker panicked");
+    }
+
+    let succeeded = attempts
+        .iter()
+        .filter(|a| a.outcome == AttemptOutcome::Success)
+        .count();
+    info!(
+        "downloaded {succeeded} of {} attempted, {total} planned",
+        attempts.len()
+    );
+    Ok(attempts)
+}
+
+/// Rendition and text tracks from what youtube-dl or yt-dlp left behind
+fn record_ytdl_meta(global_config: &GlobalConfig, video_id: &str) -> SResult<()> {
+    let backend = &global_config.downloader;
+    record_ytdl_renditions(backend, &global_config.quality, video_id)?;
+    if global_config.subtitles != SubtitleMode::None {
+        let tracks = find_text_tracks(backend, video_id)?;
+        record_text_tracks(video_id, global_config.subtitles, tracks)?;
+    }
+    Ok(())
+}
+
+/// Double check a reported success against the directory
+fn attempt_state(backend: &DownloaderBackend, attempt: &DownloadAttempt) -> SResult<VideoState> {
+    Ok(match attempt.outcome {
+        AttemptOutcome::Success => match inspect_video_dir(backend, &attempt.video_id)? {
+            VideoState::New => VideoState::Partial("reported success but left nothing".into()),
+            state => state,
+        },
+        AttemptOutcome::Failed => {
+            let class = attempt.failure.unwrap_or(FailureClass::Unknown);
+            let reason = match (&attempt.failure_evidence, attempt.exit_code) {
+                (Some(evidence), _) => format!("{}: {evidence}", class.as_ref()),
+                (None, Some(code)) => format!("{}: exit code {code}", class.as_ref()),
+                (None, None) => class.as_ref().to_string(),
+            };
+            if class.is_permanent() {
+                VideoState::Unavailable(reason)
+            } else {
+                VideoState::Failed(reason)
+            }
+        }
+        AttemptOutcome::TimedOut => VideoState::Partial("timed out".into()),
+        AttemptOutcome::Interrupted => VideoState::Partial("interrupted".into()),
+    })
+}
+
+fn download_worker(
+    backend: &DownloaderBackend,
+    quality: &QualityPolicy,
+    subtitles: SubtitleMode,
+    mut downloader: Option<Downloader>,
+    queue: &Mutex<JobQueue>,
+    sender: &Sender<WorkerEvent>,
+    options: &DownloadOptions,
+) {
+    loop {
+        if is_shutdown() {
+            return;
+        }
+        let next = queue.lock().unwrap().next();
+        let QueuedJob { job, attempt, .. } = match next {
+            NextJob::Ready(queued) => queued,
+            NextJob::Wait => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            NextJob::Done => return,
+        };
+        let in_flight = InFlight(queue);
+        if sender
+            .send(WorkerEvent::Started(job.video_id.clone()))
+            .is_err()
+        {
+            return;
+        }
+        let started = Utc::now();
+        let result = catch_unwind(AssertUnwindSafe(|| match (backend, downloader.as_mut()) {
+            (DownloaderBackend::Native, Some(downloader)) => {
+                download_one_native(downloader, &job, quality, subtitles, options.timeout)
+            }
+            _ => download_one(&job, options.timeout),
+        }));
+        let log_path = job.video_root.join(YTDL_LOG_NAME);
+        let (outcome, exit_code) = match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => {
+                error!("failed to run downloader for {}: {e}", job.video_id);
+                (AttemptOutcome::Failed, None)
+            }
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("unknown panic");
+                error!("download of {} panicked: {message}", job.video_id);
+                // the evidence for the failure report
+                if let Ok(mut log_file) = OpenOptions::new().append(true).open(&log_path) {
+                    let _ = writeln!(log_file, "ERROR: panicked: {message}");
+                }
+                (AttemptOutcome::Failed, None)
+            }
+        };
+        let log = std::fs::read_to_string(&log_path).unwrap_or_default();
+        let media_file = backend.parse_media_file(&log);
+        let failure = match outcome {
+            AttemptOutcome::Failed => Some(classify_failure(&log)),
+            AttemptOutcome::TimedOut => Some(Classified {
+                class: FailureClass::Network,
+                evidence: Some(format!("timed out after {}s", options.timeout.as_secs())),
+            }),
+            AttemptOutcome::Success | AttemptOutcome::Interrupted => None,
+        };
+        let retry_in = failure
+            .as_ref()
+            .filter(|f| f.class.is_transient() && attempt <= options.retries && !is_shutdown())
+            .map(|_| retry_backoff(options.retry_backoff, attempt));
+
+        let video_id = job.video_id.clone();
+        if let Some(retry_in) = retry_in {
+            queue.lock().unwrap().waiting.push_back(QueuedJob {
+                job,
+                attempt: attempt + 1,
+                not_before: Instant::now() + retry_in,
+            });
+        }
+        drop(in_flight);
+
+        let attempt = DownloadAttempt {
+            video_id,
+            started,
+            finished: Utc::now(),
+            exit_code,
+            outcome,
+            media_file,
+            attempt,
+            failure: failure.as_ref().map(|f| f.class),
+            failure_evidence: failure.and_then(|f| f.evidence),
+            retry_in_secs: retry_in.map(|d| d.as_secs()),
+        };
+        if sender.send(WorkerEvent::Finished(attempt)).is_err() {
+            return;
+        }
+
+        let delay_end = Instant::now() + options.delay;
+        while Instant::now() < delay_end && !is_shutdown() {
+            thread::sleep(POLL_INTERVAL.min(options.delay));
+        }
+    }
+}
+
+fn download_one(job: &DownloadJob, timeout: Duration) -> SResult<(AttemptOutcome, Option<i32>)> {
+    info!("downloading {} {}", job.video_id, job.title);
+    let log_path = job.video_root.join(YTDL_LOG_NAME);
+    let log_file = File::create(&log_path).map_err(SError::io(&log_path))?;
+    let log_file_err = log_file.try_clone().map_err(SError::io(&log_path))?;
+
+    let mut child = job
+        .command()
+        .stdin(Stdio::null())
+        .stdout(log_file)
+        .stderr(log_file_err)
+        .spawn()
+        .map_err(SError::io(&job.video_root))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().map_err(SError::io(&log_path))? {
+            let outcome = if status.success() {
+                // same marker the exported script uses
+                let marker_path = job.video_root.join(DONE_MARKER_NAME);
+                File::create(&marker_path).map_err(SError::io(&marker_path))?;
+                AttemptOutcome::Success
+            } else if is_shutdown() {
+                // the terminal sends Ctrl-C to the child too
+                AttemptOutcome::Interrupted
+            } else {
+                AttemptOutcome::Failed
+            };
+            return Ok((outcome, status.code()));
+        }
+
+        let outcome = if is_shutdown() {
+            AttemptOutcome::Interrupted
+        } else if Instant::now() > deadline {
+            warn!("timeout after {}s for {}", timeout.as_secs(), job.video_id);
+            AttemptOutcome::TimedOut
+        } else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        // already exited is fine
+        let _ = child.kill();
+        child.wait().map_err(SError::io(&log_path))?;
+        return Ok((outcome, None));
+    }
+}
+
+/// Same outcomes as [download_one], without an exit code
+fn download_one_native(
+    downloader: &mut Downloader,
+    job: &DownloadJob,
+    quality: &QualityPolicy,
+    subtitles: SubtitleMode,
+    timeout: Duration,
+) -> SResult<(AttemptOutcome, Option<i32>)> {
+    info!("downloading {} {}", job.video_id, job.title);
+    let log_path = job.video_root.join(YTDL_LOG_NAME);
+    let mut log_file = File::create(&log_path).map_err(SError::io(&log_path))?;
+
+    let deadline = Instant::now() + timeout;
+    let stop = || is_shutdown() || Instant::now() > deadline;
+    let outcome = match download_native(downloader, job, quality, subtitles, &stop, &mut log_file) {
+        Ok(Some(_)) => {
+            let marker_path = job.video_root.join(DONE_MARKER_NAME);
+            File::create(&marker_path).map_err(SError::io(&marker_path))?;
+            AttemptOutcome::Success
+        }
+        Ok(None) if is_shutdown() => AttemptOutcome::Interrupted,
+        Ok(None) => {
+            warn!("timeout after {}s for {}", timeout.as_secs(), job.video_id);
+            AttemptOutcome::TimedOut
+        }
+        Err(e) => {
+            // keep the reason next to the video like the external downloaders do
+            writeln!(log_file, "ERROR: {e}").map_err(SError::io(&log_path))?;
+            error!("native download of {} failed: {e}", job.video_id);
+            AttemptOutcome::Failed
+        }
+    };
+    Ok((outcome, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let minute = Duration::from_mins(1);
+        assert_eq!(retry_backoff(minute, 1), minute);
+        assert_eq!(retry_backoff(minute, 3), minute * 4);
+        assert_eq!(retry_backoff(minute, 10), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(minute, 33), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(Duration::MAX, 2), MAX_RETRY_BACKOFF);
+    }
+}
@@ -1,45 +1,68 @@
 use crate::err::{SError, SResult};
 use crate::global_config::GlobalConfig;
+use reqwest::header::ACCEPT;
 use reqwest::{Proxy, StatusCode};
-use std::fs::{create_dir, read, write};
+use std::fs::{create_dir_all, read, write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::{Duration, Instant};
 use strum::{AsRefStr, VariantArray};
 use tracing::{debug, error, info, trace, warn};
 
+/// Clones share the throttle, so parallel workers still keep to one request rate
+#[derive(Clone)]
 pub struct Downloader {
     client: reqwest::blocking::Client,
-    last_request: Instant,
+    last_request: Arc<Mutex<Instant>>,
     config_domain: String,
+    bc_account_id: String,
+    /// Only the playback API needs it
+    bc_policy_key: Option<String>,
+    /// Cache only
+    offline: bool,
+    /// Cache only, and report what would have been fetched
+    dry_run: bool,
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, VariantArray, AsRefStr)]
 pub enum DownType {
-    HTML,
+    Html,
     Collection,
     Page,
+    /// Brightcove playback API, sources and metadata of one video
+    Playback,
 }
 
 pub struct FetchResponse {
     pub body: Vec<u8>,
-    pub output_path: PathBuf,
 }
 
 pub const EXTRACTION_DB_ROOT: &str = "extraction-db";
 pub const VIDEO_DL_NAME: &str = "vid-dl";
 pub const BROWSE_NAME: &str = "browse";
+pub const BROWSE_SHOWS_NAME: &str = "browse-shows";
+pub const BROWSE_COLLECTIONS_NAME: &str = "browse-collections";
+pub const BROWSE_MONTHS_NAME: &str = "browse-months";
+pub const CRAWL_STATE_NAME: &str = "crawl-state.json";
+pub const CRAWLS_NAME: &str = "crawls";
+pub const CHANGELOG_NAME: &str = "changelog";
 const REQUEST_THROTTLE: Duration = Duration::from_secs(5); // Please be a nice scraper
+/// Media segments come from the CDN, not the site, but still no hammering
+pub const SEGMENT_THROTTLE: Duration = Duration::from_millis(500);
 
 impl Downloader {
-    pub fn init(global_config: &GlobalConfig) -> Self {
-        let proxy_addr = std::env::var("WARC_PROXY")
-            .expect("Please be nice, export WARC_PROXY=127.0.0.1:8000 pointing to warcprox");
+    pub fn init(global_config: &GlobalConfig, offline: bool, dry_run: bool) -> Self {
+        let mut builder = reqwest::blocking::Client::builder();
+        if !offline && !dry_run {
+            let proxy_addr = std::env::var("WARC_PROXY")
+                .expect("Please be nice, export WARC_PROXY=127.0.0.1:8000 pointing to warcprox");
+            // proxy to MITM warcprox
+            builder = builder.proxy(Proxy::all(format!("http://{proxy_addr}")).unwrap());
+        }
 
         Self {
-            client: reqwest::blocking::Client::builder()
-                // proxy to MITM warcprox
-                .proxy(Proxy::all(format!("http://{proxy_addr}")).unwrap())
+            client: builder
                 // which uses self-signed CA
                 .danger_accept_invalid_certs(true)
                 // increase timeout. I think the proxy buffers the whole response first
@@ -48,16 +71,38 @@ impl Downloader {
                 .build()
                 .unwrap(),
             // arbitrary old date
-            last_request: Instant::now() - Duration::from_days(1),
+            last_request: Arc::new(Mutex::new(Instant::now() - Duration::from_days(1))),
             config_domain: global_config.domain.clone(),
+            bc_account_id: global_config.bc_account_id.clone(),
+            bc_policy_key: global_config.bc_policy_key.clone(),
+            offline,
+            dry_run,
         }
     }
 
-    pub fn fetch(&mut self, downtype: DownType, extra: &str) -> SResult<FetchResponse> {
+    /// Like [Self::fetch], but in dry-run mode only reports uncached urls and gives None
+    pub fn fetch_or_plan(
+        &mut self,
+        downtype: DownType,
+        extra: &str,
+    ) -> SResult<Option<FetchResponse>> {
+        if self.dry_run {
+            let (url, cache_path) = self.locate(&downtype, extra);
+            if cache_path.exists() {
+                println!("dry-run: would read {url} from cache");
+            } else {
+                println!("dry-run: would fetch {url}");
+                return Ok(None);
+            }
+        }
+        self.fetch(downtype, extra).map(Some)
+    }
+
+    fn locate(&self, downtype: &DownType, extra: &str) -> (String, PathBuf) {
         let config_domain = &self.config_domain;
         let safe_name: String;
         let url = match downtype {
-            DownType::HTML => {
+            DownType::Html => {
                 assert_eq!(extra, "");
                 safe_name = "page_home".into();
                 format!("https://{config_domain}/")
@@ -70,76 +115,142 @@ impl Downloader {
                 safe_name = format!("frontend_{extra}");
                 format!("https://{config_domain}/api/core/page/{extra}")
             }
+            DownType::Playback => {
+                safe_name = format!("playback_{extra}.json");
+                let account_id = &self.bc_account_id;
+                format!(
+                    "https://edge.api.brightcove.com/playback/v1/accounts/{account_id}/videos/{extra}"
+                )
+            }
         };
-        let cache_path = path([EXTRACTION_DB_ROOT, &downtype.safe_name(), &safe_name]);
+        let cache_path = db_path([&downtype.safe_name(), &safe_name]);
+        (url, cache_path)
+    }
+
+    pub fn fetch(&mut self, downtype: DownType, extra: &str) -> SResult<FetchResponse> {
+        let (url, cache_path) = self.locate(&downtype, extra);
         if cache_path.exists() {
             debug!("cached url {url} at {}", cache_path.display());
             Ok(FetchResponse {
                 body: read(&cache_path).map_err(SError::io(&cache_path))?,
-                output_path: cache_path,
             })
         } else {
-            debug!("writing url {url} to {}", cache_path.display());
-
-            let mut body = None;
-            for i in 0..2 {
-                if i != 0 {
-                    warn!("retry {i}");
-                }
-                let throttle_safe: Instant = self.last_request + REQUEST_THROTTLE;
-                let throttle_cur = Instant::now();
-                let sleep_dur = throttle_safe - throttle_cur;
-                if sleep_dur.as_secs() > 0 {
-                    debug!("Throttle for {} secs", sleep_dur.as_secs());
-                    thread::sleep(sleep_dur);
-                }
-
-                let request = self.client.get(&url).build()?;
-                trace!("total headers {}", request.headers().len());
-                for (name, value) in request.headers() {
-                    trace!("HEADER {} - {}", name.to_string(), value.to_str().unwrap());
-                }
-                let response = self.client.execute(request)?;
-                if response.status() != StatusCode::OK {
-                    error!("bad response {}", response.status());
-                    continue;
-                }
-                body = Some(response.bytes()?);
-                break;
+            self.fetch_fresh(downtype, extra)
+        }
+    }
+
+    /// Ignore and overwrite the cache, for responses with expiring urls inside
+    pub fn fetch_fresh(&mut self, downtype: DownType, extra: &str) -> SResult<FetchResponse> {
+        let (url, cache_path) = self.locate(&downtype, extra);
+        if self.offline || self.dry_run {
+            return Err(SError::offline(url));
+        }
+        debug!("writing url {url} to {}", cache_path.display());
+
+        let accept = match downtype {
+            DownType::Playback => {
+                let policy_key = self
+                    .bc_policy_key
+                    .as_ref()
+                    .expect("BC_POLICY_KEY for the playback API");
+                Some(format!("application/json;pk={policy_key}"))
             }
-            let Some(body) = body else {
-                panic!("failed to download {url}")
+            _ => None,
+        };
+        let body = self.get_with_retry(&url, accept.as_deref(), REQUEST_THROTTLE)?;
+        write(&cache_path, &body).map_err(SError::io(&cache_path))?;
+        Ok(FetchResponse { body })
+    }
+
+    /// Uncached, for playlists and media segments
+    pub fn fetch_url(&mut self, url: &str) -> SResult<Vec<u8>> {
+        if self.offline || self.dry_run {
+            return Err(SError::offline(url));
+        }
+        trace!("fetching {url}");
+        self.get_with_retry(url, None, SEGMENT_THROTTLE)
+    }
+
+    fn get_with_retry(
+        &mut self,
+        url: &str,
+        accept: Option<&str>,
+        throttle: Duration,
+    ) -> SResult<Vec<u8>> {
+        let mut status = StatusCode::OK;
+        for i in 0..2 {
+            if i != 0 {
+                warn!("retry {i}");
+            }
+            // reserve the next slot, other clones wait behind it
+            let throttle_safe = {
+                let mut last_request = self.last_request.lock().unwrap();
+                *last_request = (*last_request + throttle).max(Instant::now());
+                *last_request
             };
-            write(&cache_path, &body).map_err(SError::io(&cache_path))?;
+            let sleep_dur = throttle_safe.saturating_duration_since(Instant::now());
+            if !sleep_dur.is_zero() {
+                trace!("Throttle for {} ms", sleep_dur.as_millis());
+                thread::sleep(sleep_dur);
+            }
 
-            self.last_request = Instant::now();
-            Ok(FetchResponse {
-                body: body.to_vec(),
-                output_path: cache_path,
-            })
+            let mut request = self.client.get(url);
+            if let Some(accept) = accept {
+                request = request.header(ACCEPT, accept);
+            }
+            let request = request.build()?;
+            trace!("total headers {}", request.headers().len());
+            for (name, value) in request.headers() {
+                trace!("HEADER {} - {}", name.to_string(), value.to_str().unwrap());
+            }
+            let response = self.client.execute(request);
+            {
+                // the throttle counts from the end of a slow response, as it always did
+                let mut last_request = self.last_request.lock().unwrap();
+                *last_request = (*last_request).max(Instant::now());
+            }
+            let response = response?;
+            status = response.status();
+            if status != StatusCode::OK {
+                error!("bad response {status} for {url}");
+                continue;
+            }
+            return Ok(response.bytes()?.to_vec());
         }
+        Err(SError::http(url, status))
     }
 }
 
 impl DownType {
-    pub fn mkdirs() {
-        let mut output_dirs: Vec<PathBuf> = Self::VARIANTS
-            .iter()
-            .map(|downtype| path([EXTRACTION_DB_ROOT, &downtype.safe_name()]))
-            .collect();
-        output_dirs.insert(0, path([EXTRACTION_DB_ROOT]));
-        output_dirs.insert(0, path([EXTRACTION_DB_ROOT, VIDEO_DL_NAME]));
-        output_dirs.insert(0, path([EXTRACTION_DB_ROOT, BROWSE_NAME]));
+    pub fn mkdirs(dry_run: bool) {
+        let mut output_dirs: Vec<PathBuf> = vec![
+            db_path([]),
+            db_path([VIDEO_DL_NAME]),
+            db_path([BROWSE_NAME]),
+            db_path([BROWSE_SHOWS_NAME]),
+            db_path([BROWSE_COLLECTIONS_NAME]),
+            db_path([BROWSE_MONTHS_NAME]),
+            db_path([CRAWLS_NAME]),
+            db_path([CHANGELOG_NAME]),
+        ];
+        output_dirs.extend(
+            Self::VARIANTS
+                .iter()
+                .map(|downtype| db_path([&downtype.safe_name()])),
+        );
 
         for dir in output_dirs {
-            if !dir.exists() {
+            if !dir.exists() && dry_run {
+                println!("dry-run: would create directory {}", dir.display());
+            } else if !dir.exists() {
                 info!("Creating directory {}", dir.display());
-                create_dir(&dir).unwrap();
+                create_dir_all(&dir).unwrap();
             }
         }
     }
 
-    fn safe_name(&self) -> String {
+    /// Cache directory name
+    pub fn safe_name(&self) -> String {
         self.as_ref().to_ascii_lowercase()
     }
 }
@@ -147,3 +258,20 @@ impl DownType {
 pub fn path<const N: usize>(input: [&str; N]) -> PathBuf {
     input.into_iter().collect()
 }
+
+static OUTPUT_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Only the first call wins, before that everything goes to [EXTRACTION_DB_ROOT]
+pub fn set_output_root(root: PathBuf) {
+    if OUTPUT_ROOT.set(root).is_err() {
+        warn!("output root already set");
+    }
+}
+
+/// Path under the configured output root
+pub fn db_path<const N: usize>(input: [&str; N]) -> PathBuf {
+    let root = OUTPUT_ROOT.get_or_init(|| EXTRACTION_DB_ROOT.into());
+    let mut output = root.clone();
+    output.extend(input);
+    output
+}
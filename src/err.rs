@@ -11,6 +11,30 @@ pub enum SError {
 
     #[error("Io {0} for {1}")]
     Io(std::io::Error, PathBuf, Backtrace),
+
+    #[error("Json {0} for {1}")]
+    Json(Box<simd_json::Error>, PathBuf, Backtrace),
+
+    #[error("Offline and not cached {0}")]
+    Offline(String, Backtrace),
+
+    #[error("No crawl snapshot yet, run crawl first")]
+    NoSnapshot(Backtrace),
+
+    #[error("Http {1} for {0}")]
+    Http(String, reqwest::StatusCode, Backtrace),
+
+    #[error("Unsupported HLS {0}")]
+    Hls(String, Backtrace),
+
+    #[error("Bag export {0}")]
+    Bag(String, Backtrace),
+
+    #[error("Crawl interrupted, continue with crawl --resume")]
+    CrawlInterrupted(Backtrace),
+
+    #[error("Browse naming changed from {0} to {1}, run browse --rename to move the links")]
+    BrowseRenamed(String, String, Backtrace),
 }
 
 impl SError {
@@ -22,10 +46,51 @@ impl SError {
         move |e| Self::Io(e, path.clone(), sbt())
     }
 
+    pub fn json(path: impl Into<PathBuf>) -> impl Fn(simd_json::Error) -> SError {
+        let path = path.into();
+        move |e| Self::Json(Box::new(e), path.clone(), sbt())
+    }
+
+    pub fn offline(url: impl Into<String>) -> SError {
+        Self::Offline(url.into(), sbt())
+    }
+
+    pub fn no_snapshot() -> SError {
+        Self::NoSnapshot(sbt())
+    }
+
+    pub fn http(url: impl Into<String>, status: reqwest::StatusCode) -> SError {
+        Self::Http(url.into(), status, sbt())
+    }
+
+    pub fn hls(reason: impl Into<String>) -> SError {
+        Self::Hls(reason.into(), sbt())
+    }
+
+    pub fn bag(reason: impl Into<String>) -> SError {
+        Self::Bag(reason.into(), sbt())
+    }
+
+    pub fn crawl_interrupted() -> SError {
+        Self::CrawlInterrupted(sbt())
+    }
+
+    pub fn browse_renamed(from: impl Into<String>, to: impl Into<String>) -> SError {
+        Self::BrowseRenamed(from.into(), to.into(), sbt())
+    }
+
     fn my_backtrace(&self) -> &Backtrace {
         match self {
             SError::Reqwest(_, bt) => bt,
             SError::Io(_, _, bt) => bt,
+            SError::Json(_, _, bt) => bt,
+            SError::Offline(_, bt) => bt,
+            SError::NoSnapshot(bt) => bt,
+            SError::Http(_, _, bt) => bt,
+            SError::Hls(_, bt) => bt,
+            SError::Bag(_, bt) => bt,
+            SError::CrawlInterrupted(bt) => bt,
+            SError::BrowseRenamed(_, _, bt) => bt,
         }
     }
 }
@@ -1,8 +1,13 @@
+use crate::crawl::NodeKey;
 use crate::err::SResult;
 use crate::utils::{get_only, last_position_of};
 use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
 use simd_json::BorrowedValue;
-use simd_json::prelude::{ValueObjectAccess, ValueObjectAccessAsArray, ValueObjectAccessAsScalar};
+use simd_json::prelude::{
+    ValueAsArray, ValueAsObject, ValueAsScalar, ValueObjectAccess, ValueObjectAccessAsArray,
+    ValueObjectAccessAsScalar,
+};
 use tracing::{debug, trace};
 
 pub fn extract_original_id(content: &[u8]) -> SResult<String> {
@@ -25,13 +30,13 @@ pub fn extract_original_id(content: &[u8]) -> SResult<String> {
     Ok(root_id.into())
 }
 
-pub fn extract_collections_from_root(mut content: Vec<u8>) -> SResult<Vec<ExtractedThing>> {
+pub fn extract_collections_from_root(mut content: Vec<u8>) -> SResult<ExtractedListing> {
     let json: BorrowedValue = simd_json::to_borrowed_value(&mut content).unwrap();
 
     // jq '.page.containerCollections[]  | .containers[] | .data.feed'
-    let container_collections = json
-        .get("page")
-        .expect("pages")
+    let page = json.get("page").expect("pages");
+    let page_meta = extract_meta(page);
+    let container_collections = page
         .get_array("containerCollections")
         .expect("containerCollections");
     let container_collection = get_only(container_collections, "containerCollections len");
@@ -59,18 +64,28 @@ pub fn extract_collections_from_root(mut content: Vec<u8>) -> SResult<Vec<Extrac
         };
         let collection_id = &feed_url[(last_position_of(feed_url, b'/') + 1)..];
         trace!("id {collection_id}");
+        // title is usually on the container, sometimes only on its data
+        let meta = extract_meta(container).or(extract_meta(container.get("data").expect("data")));
         feeds.push(ExtractedThing {
             next_id: collection_id.into(),
             next_type: ThingType::Collection,
-            title: "collection from page".into(),
+            meta,
         });
     }
 
-    Ok(feeds)
+    Ok(ExtractedListing {
+        meta: page_meta,
+        things: feeds,
+    })
 }
 
-pub fn extract_things_from_collection(mut content: Vec<u8>) -> SResult<Vec<ExtractedThing>> {
+pub fn extract_things_from_collection(mut content: Vec<u8>) -> SResult<ExtractedListing> {
     let json: BorrowedValue = simd_json::to_borrowed_value(&mut content).unwrap();
+    // older responses nest the collection itself, newer put it at the top
+    let collection_meta = match json.get("collection") {
+        Some(collection) => extract_meta(collection),
+        None => extract_meta(&json),
+    };
 
     let has_next = json
         .get("pageInfo")
@@ -86,7 +101,7 @@ pub fn extract_things_from_collection(mut content: Vec<u8>) -> SResult<Vec<Extra
     let data_arr = json.get_array("data").expect("data");
     let mut video_ids = Vec::new();
     for item in data_arr {
-        let title = item.get_str("title").expect("title");
+        let meta = extract_meta(item);
         if let Some(actions) = item.get_array("actions") {
             let action = get_only(actions, "actions value");
             assert_eq!(action.get_str("kind").expect("kind"), "NAVIGATE_TO_PAGE");
@@ -97,7 +112,7 @@ pub fn extract_things_from_collection(mut content: Vec<u8>) -> SResult<Vec<Extra
                 .get_str("id")
                 .expect("id");
             video_ids.push(ExtractedThing {
-                title: title.into(),
+                meta: meta.clone(),
                 next_id: id.into(),
                 next_type: ThingType::Page,
             })
@@ -113,23 +128,112 @@ pub fn extract_things_from_collection(mut content: Vec<u8>) -> SResult<Vec<Extra
         };
         trace!("found video {id}");
         video_ids.push(ExtractedThing {
-            title: title.into(),
+            meta,
             next_id: id.into(),
             next_type: ThingType::Video,
         });
     }
 
-    Ok(video_ids)
+    Ok(ExtractedListing {
+        meta: collection_meta,
+        things: video_ids,
+    })
+}
+
+/// Human facing fields shared by pages, collections, and video items
+fn extract_meta(value: &BorrowedValue) -> ThingMeta {
+    let non_empty = |v: Option<&str>| v.map(str::trim).filter(|v| !v.is_empty()).map(String::from);
+    ThingMeta {
+        title: non_empty(value.get_str("title")),
+        description: non_empty(value.get_str("description"))
+            .or_else(|| non_empty(value.get_str("shortDescription"))),
+        artwork: extract_artwork(value),
+        season: extract_number(value, "seasonNumber"),
+        episode: extract_number(value, "episodeNumber"),
+    }
+}
+
+/// Numbers are sometimes strings, and sometimes only on the nested video
+fn extract_number(value: &BorrowedValue, key: &str) -> Option<u32> {
+    let parse = |value: &BorrowedValue| -> Option<u32> {
+        let raw = value.get(key)?;
+        raw.as_u32().or_else(|| raw.as_str()?.trim().parse().ok())
+    };
+    parse(value).or_else(|| parse(value.get("video")?))
+}
+
+fn extract_artwork(value: &BorrowedValue) -> Option<String> {
+    let url_of = |image: &BorrowedValue| -> Option<String> {
+        image
+            .as_str()
+            .or_else(|| image.get_str("url"))
+            .or_else(|| image.get_str("src"))
+            .map(String::from)
+    };
+
+    // Seen as {"thumbnail": "..", "wallpaper": ".."} and as [{"url": ".."}]
+    let images = value.get("images")?;
+    if let Some(images) = images.as_object() {
+        ["thumbnail", "poster", "wallpaper", "hero"]
+            .iter()
+            .find_map(|kind| images.get(*kind).and_then(url_of))
+            .or_else(|| images.values().find_map(url_of))
+    } else if let Some(images) = images.as_array() {
+        images.iter().find_map(url_of)
+    } else {
+        url_of(images)
+    }
+}
+
+#[derive(Clone, Default, Debug, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ThingMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub artwork: Option<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+impl ThingMeta {
+    /// Fill any missing field from the fallback
+    pub fn or(self, fallback: ThingMeta) -> ThingMeta {
+        ThingMeta {
+            title: self.title.or(fallback.title),
+            description: self.description.or(fallback.description),
+            artwork: self.artwork.or(fallback.artwork),
+            season: self.season.or(fallback.season),
+            episode: self.episode.or(fallback.episode),
+        }
+    }
+
+    pub fn display_title<'a>(&'a self, id: &'a str) -> &'a str {
+        self.title.as_deref().unwrap_or(id)
+    }
+}
+
+pub struct ExtractedListing {
+    /// Metadata of the page or collection itself
+    pub meta: ThingMeta,
+    pub things: Vec<ExtractedThing>,
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ExtractedThing {
-    pub title: String,
+    pub meta: ThingMeta,
     pub next_type: ThingType,
     pub next_id: String,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
+impl ExtractedThing {
+    pub fn key(&self) -> NodeKey {
+        NodeKey {
+            thing_type: self.next_type,
+            id: self.next_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
 pub enum ThingType {
     Video,
     Collection,
@@ -0,0 +1,260 @@
+use crate::crawl::CrawlResult;
+use crate::downloader::db_path;
+use crate::err::{SError, SResult};
+use crate::video_dl::{DownloadStates, VideoState};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::LazyLock;
+use strum::AsRefStr;
+use tracing::info;
+
+/// Why a download failed, guessed from the downloader's log
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum FailureClass {
+    GeoBlocked,
+    Drm,
+    Removed,
+    AuthExpired,
+    Network,
+    Unknown,
+}
+
+pub struct Classified {
+    pub class: FailureClass,
+    /// The log line that gave it away
+    pub evidence: Option<String>,
+}
+
+/// Checked in order, the first matching line wins. Covers youtube-dl, yt-dlp,
+/// the Brightcove playback API error codes, and our own native downloader. 404s only count as
+/// removed from the playback or catalog API or youtube-dl's metadata requests, a missing
+/// fragment, segment, or poster proves nothing
+static PATTERNS: LazyLock<Vec<(FailureClass, Regex)>> = LazyLock::new(|| {
+    [
+        (
+            FailureClass::GeoBlocked,
+            r"(?i)not available (in your country|from your location)|geo.?restrict|CLIENT_GEO",
+        ),
+        (
+            FailureClass::Drm,
+            r"(?i)\bDRM\b|Unsupported HLS encrypted|widevine|playready|fairplay",
+        ),
+        (
+            FailureClass::Removed,
+            r"(?i)Unable to download (JSON|webpage|API)[^:]*: HTTP Error 404|Http 404 .* for \S*/(playback/v1/accounts|api/core/catalog)/|VIDEO_NOT_FOUND|video (is )?(not found|unavailable)|has been removed|no longer available",
+        ),
+        (
+            FailureClass::AuthExpired,
+            r"(?i)HTTP Error 40[13]|Http 40[13]|ACCESS_DENIED|TOKEN_EXPIRED|INVALID_POLICY_KEY|log ?in required|sign in",
+        ),
+        (
+            FailureClass::Network,
+            r"(?i)timed? ?out|connection (reset|refused|aborted)|name resolution|IncompleteRead|HTTP Error 5\d\d|Http 5\d\d|Unable to download|Reqwest",
+        ),
+    ]
+    .into_iter()
+    .map(|(class, pattern)| (class, Regex::new(pattern).unwrap()))
+    .collect()
+});
+
+impl FailureClass {
+    /// Worth retrying in the same run
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FailureClass::Network | FailureClass::Unknown)
+    }
+
+    /// Never going to work, stop trying
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            FailureClass::GeoBlocked | FailureClass::Drm | FailureClass::Removed
+        )
+    }
+}
+
+/// Error lines are checked first, then the whole log from the end. Permanent classes only come
+/// from error lines, titles and destinations elsewhere in the log can say "removed" or "DRM"
+pub fn classify_failure(log: &str) -> Classified {
+    let error_lines = log
+        .lines()
+        .filter(|line| line.contains("ERROR"))
+        .map(|line| (line, true));
+    let all_lines = log.lines().rev().map(|line| (line, false));
+    for (line, is_error) in error_lines.chain(all_lines) {
+        if let Some((class, _)) = PATTERNS
+            .iter()
+            .find(|(class, regex)| (is_error || !class.is_permanent()) && regex.is_match(line))
+        {
+            return Classified {
+                class: *class,
+                evidence: Some(line.trim().to_string()),
+            };
+        }
+    }
+    Classified {
+        class: FailureClass::Unknown,
+        evidence: log
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| line.trim().to_string()),
+    }
+}
+
+pub const FAILURE_REPORT_NAME: &str = "failure-report.md";
+
+/// Failed and unavailable videos grouped by class, titles from the crawl when there is one
+pub fn failure_report(states: &DownloadStates, crawl: Option<&CrawlResult>) -> String {
+    let titles: HashMap<&str, &str> = crawl
+        .map(|crawl| crawl.videos().map(|v| (v.id.as_str(), v.title())).collect())
+        .unwrap_or_default();
+    let mut permanent = Vec::new();
+    let mut retried = Vec::new();
+    for (video_id, record) in states.iter() {
+        let (reason, list) = match &record.state {
+            VideoState::Unavailable(reason) => (reason, &mut permanent),
+            VideoState::Failed(reason) => (reason, &mut retried),
+            _ => continue,
+        };
+        list.push((
+            record.failure.unwrap_or(FailureClass::Unknown),
+            video_id,
+            record.attempts,
+            reason,
+        ));
+    }
+    permanent.sort();
+    retried.sort();
+
+    let mut md = "# Download failures\n".to_string();
+    for (heading, list) in [
+        ("Permanent, marked unavailable", &permanent),
+        ("Retried on the next download run", &retried),
+    ] {
+        writeln!(md, "\n## {heading} ({})\n", list.len()).unwrap();
+        for (class, video_id, attempts, reason) in list {
+            let title = titles.get(video_id.as_str()).copied().unwrap_or("");
+            writeln!(
+                md,
+                "- {} `{video_id}` {title} after {attempts} attempts: {reason}",
+                class.as_ref()
+            )
+            .unwrap();
+        }
+    }
+    md
+}
+
+pub fn write_failure_report(
+    states: &DownloadStates,
+    crawl: Option<&CrawlResult>,
+) -> SResult<String> {
+    let report = failure_report(states, crawl);
+    let report_path = db_path([FAILURE_REPORT_NAME]);
+    std::fs::write(&report_path, &report).map_err(SError::io(&report_path))?;
+    info!("wrote failure report to {}", report_path.display());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYBACK_404: &str = "ERROR: Http 404 Not Found for https://edge.api.brightcove.com/playback/v1/accounts/1234/videos/6300000000001";
+
+    #[test]
+    fn title_saying_removed_is_not_evidence() {
+        let log = "[brightcove] 6300000000001: Downloading JSON metadata\n\
+            [info] Title: Scenes removed from the DRM cut, not available in your country\n\
+            [download] Destination: Scenes removed from the DRM cut.mp4\n\
+            ERROR: unable to write data: [Errno 28] No space left on device\n";
+        let classified = classify_failure(log);
+        assert_eq!(classified.class, FailureClass::Unknown);
+        assert_eq!(
+            classified.evidence.as_deref(),
+            Some("ERROR: unable to write data: [Errno 28] No space left on device")
+        );
+    }
+
+    #[test]
+    fn transient_classes_come_from_any_line() {
+        let log = "[info] Title: The removed scenes\n\
+            [download] Got server HTTP error: Connection reset by peer. Retrying (attempt 1 of 10)\n\
+            [download]  42.0% of 1.20GiB\n";
+        let classified = classify_failure(log);
+        assert_eq!(classified.class, FailureClass::Network);
+        assert!(classified.evidence.unwrap().contains("Connection reset"));
+    }
+
+    #[test]
+    fn error_lines_win() {
+        let log = "[download] Got server HTTP error: Connection reset by peer. Retrying\n\
+            ERROR: [brightcove] 6300000000001: This video has been removed by the uploader\n";
+        assert_eq!(classify_failure(log).class, FailureClass::Removed);
+        let log = "ERROR: [brightcove] 6300000000001: The uploader has not made this video \
+            available in your country. This video is not available from your location\n";
+        assert_eq!(classify_failure(log).class, FailureClass::GeoBlocked);
+        assert_eq!(
+            classify_failure("ERROR: This video is DRM protected\n").class,
+            FailureClass::Drm
+        );
+    }
+
+    #[test]
+    fn only_api_404s_mean_removed() {
+        let classified = classify_failure(PLAYBACK_404);
+        assert_eq!(classified.class, FailureClass::Removed);
+        assert_eq!(classified.evidence.as_deref(), Some(PLAYBACK_404));
+
+        let segment = "ERROR: Http 404 Not Found for https://house-fastly.example.com/media/v1/hls/v4/clear/1234/seg/00012.ts";
+        assert_eq!(classify_failure(segment).class, FailureClass::Unknown);
+        let poster =
+            "ERROR: Http 404 Not Found for https://cf-images.example.com/image/1234/poster.jpg";
+        assert_eq!(classify_failure(poster).class, FailureClass::Unknown);
+        // the same 404 outside an error line proves nothing either
+        let quoted = &PLAYBACK_404["ERROR: ".len()..];
+        assert_eq!(classify_failure(quoted).class, FailureClass::Unknown);
+    }
+
+    #[test]
+    fn youtube_dl_404s() {
+        let metadata = "ERROR: [brightcove:new] 6300000000001: Unable to download JSON metadata: HTTP Error 404: Not Found (caused by <HTTPError 404: 'Not Found'>)";
+        assert_eq!(classify_failure(metadata).class, FailureClass::Removed);
+
+        let fragment = "[download] Got server HTTP error: HTTP Error 404: Not Found. Retrying fragment 12 (attempt 10 of 10)...\n\
+            ERROR: unable to download video data: HTTP Error 404: Not Found\n";
+        let classified = classify_failure(fragment);
+        assert!(!classified.class.is_permanent());
+        assert_eq!(classified.class, FailureClass::Network);
+        let thumbnail = "ERROR: Unable to download thumbnail: HTTP Error 404: Not Found\n";
+        assert!(!classify_failure(thumbnail).class.is_permanent());
+    }
+
+    #[test]
+    fn expired_auth_and_server_errors() {
+        assert_eq!(
+            classify_failure("ERROR: Http 403 Forbidden for https://edge.api.brightcove.com/playback/v1/accounts/1234/videos/1\n").class,
+            FailureClass::AuthExpired
+        );
+        assert_eq!(
+            classify_failure(
+                "ERROR: Http 503 Service Unavailable for https://cdn.example.com/seg.ts\n"
+            )
+            .class,
+            FailureClass::Network
+        );
+        assert!(FailureClass::Network.is_transient());
+        assert!(!FailureClass::AuthExpired.is_permanent());
+    }
+
+    #[test]
+    fn empty_log() {
+        let classified = classify_failure("\n  \n");
+        assert_eq!(classified.class, FailureClass::Unknown);
+        assert_eq!(classified.evidence, None);
+    }
+}
@@ -1,4 +1,11 @@
+use crate::backend::DownloaderBackend;
 use crate::err::{SError, SResult};
+use crate::hierarchy::DEFAULT_EPISODE_REGEXES;
+use crate::naming::NamingTemplate;
+use crate::quality::QualityPolicy;
+use crate::sanitize::Sanitizer;
+use crate::subtitles::SubtitleMode;
+use regex::Regex;
 use std::collections::HashMap;
 use std::path::Path;
 
@@ -6,18 +13,35 @@ use std::path::Path;
 pub struct GlobalConfig {
     pub domain: String,
     pub bc_account_id: String,
+    /// BC_POLICY_KEY= from the player config, only DOWNLOADER=native and recheck need it
+    pub bc_policy_key: Option<String>,
     pub missing_videos: Vec<String>,
+    /// Title fallbacks for season/episode numbers, first match wins
+    pub episode_regexes: Vec<Regex>,
+    /// DOWNLOADER= youtube-dl (default), yt-dlp, custom with DOWNLOADER_TEMPLATE=, or native
+    pub downloader: DownloaderBackend,
+    /// QUALITY= and the QUALITY_ caps and preferences, for every backend
+    pub quality: QualityPolicy,
+    /// SUBTITLES= which caption, subtitle, and chapter tracks to keep
+    pub subtitles: SubtitleMode,
+    /// live_collection= lines, schedule or live rails the live capture polls
+    pub live_collections: Vec<String>,
+    /// FILENAME_PROFILE= and FILENAME_MAX_BYTES= for browse names
+    pub sanitizer: Sanitizer,
+    /// BROWSE_TEMPLATE= and BROWSE_TIMEZONE= for browse names
+    pub browse_naming: NamingTemplate,
 }
 
 impl GlobalConfig {
-    pub fn load() -> SResult<Self> {
-        let path = Path::new(".env");
+    pub fn load(path: &Path) -> SResult<Self> {
         let raw = std::fs::read(path).map_err(SError::io(path))?;
 
         let lines_raw = String::from_utf8(raw).unwrap();
 
         let mut config_map = HashMap::new();
         let mut missing_videos = Vec::new();
+        let mut episode_regexes = Vec::new();
+        let mut live_collections = Vec::new();
         for line in lines_raw.lines() {
             if line.starts_with("#") {
                 continue;
@@ -25,16 +49,56 @@ impl GlobalConfig {
             let (k, v) = line.split_once("=").unwrap();
             if k == "missing" {
                 missing_videos.push(v.to_string());
+            } else if k == "episode_regex" {
+                episode_regexes.push(Regex::new(v).expect("bad episode_regex"));
+            } else if k == "live_collection" {
+                live_collections.push(v.to_string());
             } else {
                 config_map.insert(k, v);
             }
         }
 
+        if episode_regexes.is_empty() {
+            episode_regexes = DEFAULT_EPISODE_REGEXES
+                .iter()
+                .map(|v| Regex::new(v).unwrap())
+                .collect();
+        }
+
         let config = Self {
             domain: config_map.remove("DOMAIN").unwrap().into(),
             bc_account_id: config_map.remove("BC_ACCOUNT_ID").unwrap().into(),
+            bc_policy_key: config_map.remove("BC_POLICY_KEY").map(String::from),
             missing_videos,
+            episode_regexes,
+            downloader: DownloaderBackend::from_config(
+                config_map.remove("DOWNLOADER"),
+                config_map.remove("DOWNLOADER_TEMPLATE"),
+            ),
+            quality: QualityPolicy::from_config(
+                config_map.remove("QUALITY"),
+                config_map.remove("QUALITY_MAX_HEIGHT"),
+                config_map.remove("QUALITY_MAX_KBPS"),
+                config_map.remove("QUALITY_CODEC"),
+                config_map.remove("QUALITY_CONTAINER"),
+            ),
+            subtitles: SubtitleMode::from_config(config_map.remove("SUBTITLES")),
+            live_collections,
+            sanitizer: Sanitizer::from_config(
+                config_map.remove("FILENAME_PROFILE"),
+                config_map.remove("FILENAME_MAX_BYTES"),
+            ),
+            browse_naming: NamingTemplate::from_config(
+                config_map.remove("BROWSE_TEMPLATE"),
+                config_map.remove("BROWSE_TIMEZONE"),
+            ),
         };
+        if matches!(config.downloader, DownloaderBackend::Native) {
+            assert!(
+                config.bc_policy_key.is_some(),
+                "DOWNLOADER=native needs BC_POLICY_KEY"
+            );
+        }
         Ok(config)
     }
 }
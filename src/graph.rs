@@ -0,0 +1,169 @@
+use crate::crawl::{CrawlResult, NodeKey};
+use crate::downloader::db_path;
+use crate::err::{SError, SResult};
+use crate::extractor::ThingType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use tracing::info;
+
+/// Every page -> collection -> video membership the spider saw,
+/// including the ones pointing at already visited nodes
+#[derive(Default, Serialize, Deserialize)]
+pub struct CrawlGraph {
+    pub edges: Vec<CrawlEdge>,
+    /// Edge positions by parent and by child, built on first lookup
+    #[serde(skip)]
+    index: OnceLock<EdgeIndex>,
+}
+
+#[derive(Default)]
+struct EdgeIndex {
+    from: HashMap<NodeKey, Vec<usize>>,
+    to: HashMap<NodeKey, Vec<usize>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CrawlEdge {
+    pub from: NodeKey,
+    pub to: NodeKey,
+    /// Index in the parent's listing
+    pub position: usize,
+    pub discovered: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct GraphExport<'c> {
+    nodes: Vec<GraphExportNode<'c>>,
+    edges: &'c [CrawlEdge],
+}
+
+#[derive(Serialize)]
+struct GraphExportNode<'c> {
+    #[serde(flatten)]
+    key: NodeKey,
+    title: &'c str,
+}
+
+impl CrawlGraph {
+    pub fn record(&mut self, from: &NodeKey, children: impl IntoIterator<Item = NodeKey>) {
+        let discovered = Utc::now();
+        self.index = OnceLock::new();
+        for (position, to) in children.into_iter().enumerate() {
+            self.edges.push(CrawlEdge {
+                from: from.clone(),
+                to,
+                position,
+                discovered,
+            });
+        }
+    }
+
+    fn index(&self) -> &EdgeIndex {
+        self.index.get_or_init(|| {
+            let mut index = EdgeIndex::default();
+            for (i, edge) in self.edges.iter().enumerate() {
+                index.from.entry(edge.from.clone()).or_default().push(i);
+                index.to.entry(edge.to.clone()).or_default().push(i);
+            }
+            index
+        })
+    }
+
+    fn edges_at<'g>(&'g self, positions: Option<&Vec<usize>>) -> Vec<&'g CrawlEdge> {
+        positions
+            .into_iter()
+            .flatten()
+            .map(|i| &self.edges[*i])
+            .collect()
+    }
+
+    /// In listing order
+    pub fn children_of(&self, key: &NodeKey) -> Vec<&CrawlEdge> {
+        let mut children = self.edges_at(self.index().from.get(key));
+        children.sort_by_key(|e| e.position);
+        children
+    }
+
+    pub fn parents_of(&self, key: &NodeKey) -> Vec<&CrawlEdge> {
+        self.edges_at(self.index().to.get(key))
+    }
+
+    pub fn collections_containing(&self, video_id: &str) -> Vec<&CrawlEdge> {
+        self.parents_of(&NodeKey {
+            thing_type: ThingType::Video,
+            id: video_id.into(),
+        })
+        .into_iter()
+        .filter(|e| e.from.thing_type == ThingType::Collection)
+        .collect()
+    }
+
+    pub fn write(&self, crawl: &CrawlResult) -> SResult<()> {
+        let export = GraphExport {
+            nodes: crawl
+                .nodes
+                .iter()
+                .map(|node| GraphExportNode {
+                    key: node.key(),
+                    title: node.title(),
+                })
+                .collect(),
+            edges: &self.edges,
+        };
+        let json_path = db_path(["graph.json"]);
+        let raw = simd_json::to_vec_pretty(&export).map_err(SError::json(&json_path))?;
+        std::fs::write(&json_path, raw).map_err(SError::io(&json_path))?;
+
+        let dot_path = db_path(["graph.dot"]);
+        std::fs::write(&dot_path, self.to_dot(crawl)).map_err(SError::io(&dot_path))?;
+
+        info!(
+            "wrote {} edges to {} and {}",
+            self.edges.len(),
+            json_path.display(),
+            dot_path.display()
+        );
+        Ok(())
+    }
+
+    pub fn to_dot(&self, crawl: &CrawlResult) -> String {
+        let mut dot = String::from("digraph crawl {\n    rankdir=LR;\n");
+        for node in &crawl.nodes {
+            let shape = match node.thing_type {
+                ThingType::Page => "box",
+                ThingType::Collection => "folder",
+                ThingType::Video => "ellipse",
+            };
+            writeln!(
+                dot,
+                "    {} [label={}, shape={shape}];",
+                dot_id(&node.key()),
+                dot_quote(node.title())
+            )
+            .unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(
+                dot,
+                "    {} -> {} [label=\"{}\"];",
+                dot_id(&edge.from),
+                dot_id(&edge.to),
+                edge.position
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn dot_id(key: &NodeKey) -> String {
+    dot_quote(&format!("{:?}:{}", key.thing_type, key.id))
+}
+
+fn dot_quote(raw: &str) -> String {
+    format!("\"{}\"", raw.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,256 @@
+use crate::crawl::{CrawlNode, CrawlResult};
+use crate::downloader::db_path;
+use crate::err::{SError, SResult};
+use crate::extractor::ThingType;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use tracing::{debug, info};
+
+/// Used when the config has no `episode_regex=` lines.
+/// Named groups `season` and `episode` are both optional.
+pub const DEFAULT_EPISODE_REGEXES: [&str; 3] = [
+    r"(?i)\bS(?<season>\d{1,3})\s*E(?<episode>\d{1,4})\b",
+    r"(?i)\bseason\s*(?<season>\d{1,3})\b",
+    r"(?i)\b(?:episode|ep\.?)\s*(?<episode>\d{1,4})\b",
+];
+
+/// An episode code the catalog title already starts with, like "S01E02 - " or "Ep. 3: "
+static LEADING_EPISODE_CODE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(?:S\d{1,3}\s*E\d{1,4}|E(?:p\.?|pisode)?\s*\d{1,4})\b[\s\-–:.]*").unwrap()
+});
+
+/// Shows are pages, seasons are the collections on them, episodes are the videos in those
+#[derive(Serialize)]
+pub struct Hierarchy {
+    pub shows: Vec<Show>,
+    /// Videos only listed in collections outside any show, eg the home page rows
+    pub unsorted: Vec<Episode>,
+}
+
+#[derive(Serialize)]
+pub struct Show {
+    pub id: String,
+    pub title: String,
+    pub seasons: Vec<Season>,
+}
+
+#[derive(Serialize)]
+pub struct Season {
+    pub id: String,
+    pub title: String,
+    pub number: Option<u32>,
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Serialize)]
+pub struct Episode {
+    pub video_id: String,
+    pub title: String,
+    pub season: Option<u32>,
+    pub number: Option<u32>,
+}
+
+pub struct EpisodeRef<'h> {
+    pub show: &'h Show,
+    pub season: &'h Season,
+    pub episode: &'h Episode,
+}
+
+impl Hierarchy {
+    pub fn build(crawl: &CrawlResult, episode_regexes: &[Regex]) -> Self {
+        let root_id = &crawl.root().id;
+        let mut placed = HashSet::new();
+
+        let mut shows = Vec::new();
+        for page in &crawl.nodes {
+            if page.thing_type != ThingType::Page || &page.id == root_id {
+                continue;
+            }
+
+            let mut seasons = Vec::new();
+            for collection in children_of(crawl, page, ThingType::Collection) {
+                let (title_season, _) = match_numbers(collection.title(), episode_regexes);
+                let number = collection.meta.season.or(title_season);
+
+                let episodes: Vec<Episode> = children_of(crawl, collection, ThingType::Video)
+                    .map(|video| {
+                        placed.insert(video.id.as_str());
+                        episode_of(video, number, episode_regexes)
+                    })
+                    .collect();
+                seasons.push(Season {
+                    id: collection.id.clone(),
+                    title: collection.title().into(),
+                    number,
+                    episodes,
+                });
+            }
+            debug!("show {} has {} seasons", page.title(), seasons.len());
+            shows.push(Show {
+                id: page.id.clone(),
+                title: page.title().into(),
+                seasons,
+            });
+        }
+
+        let unsorted = crawl
+            .videos()
+            .filter(|video| !placed.contains(video.id.as_str()))
+            .map(|video| episode_of(video, None, episode_regexes))
+            .collect();
+
+        Hierarchy { shows, unsorted }
+    }
+
+    /// The first show and season listing this video
+    pub fn locate(&self, video_id: &str) -> Option<EpisodeRef<'_>> {
+        self.shows.iter().find_map(|show| {
+            show.seasons.iter().find_map(|season| {
+                season
+                    .episodes
+                    .iter()
+                    .find(|episode| episode.video_id == video_id)
+                    .map(|episode| EpisodeRef {
+                        show,
+                        season,
+                        episode,
+                    })
+            })
+        })
+    }
+
+    pub fn write(&self) -> SResult<()> {
+        let hierarchy_path = db_path(["hierarchy.json"]);
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&hierarchy_path))?;
+        std::fs::write(&hierarchy_path, raw).map_err(SError::io(&hierarchy_path))?;
+        info!(
+            "wrote {} shows and {} unsorted videos to {}",
+            self.shows.len(),
+            self.unsorted.len(),
+            hierarchy_path.display()
+        );
+        Ok(())
+    }
+}
+
+impl Season {
+    pub fn display_name(&self) -> String {
+        match self.number {
+            Some(number) => format!("Season {number:02}"),
+            None => self.title.clone(),
+        }
+    }
+}
+
+impl Episode {
+    /// The code in front, once. A code the title starts with is replaced
+    pub fn display_name(&self) -> String {
+        let title = LEADING_EPISODE_CODE.replace(&self.title, "");
+        let code = match (self.season, self.number) {
+            (Some(season), Some(number)) => format!("S{season:02}E{number:02}"),
+            (None, Some(number)) => format!("E{number:02}"),
+            _ => return self.title.clone(),
+        };
+        if title.is_empty() {
+            code
+        } else {
+            format!("{code} {title}")
+        }
+    }
+}
+
+fn children_of<'c>(
+    crawl: &'c CrawlResult,
+    node: &'c CrawlNode,
+    thing_type: ThingType,
+) -> impl Iterator<Item = &'c CrawlNode> {
+    crawl
+        .graph
+        .children_of(&node.key())
+        .into_iter()
+        .filter(move |edge| edge.to.thing_type == thing_type)
+        .filter_map(|edge| crawl.get(&edge.to))
+}
+
+/// Site metadata wins, then the title, then the season collection
+fn episode_of(video: &CrawlNode, season: Option<u32>, episode_regexes: &[Regex]) -> Episode {
+    let (title_season, title_episode) = match_numbers(video.title(), episode_regexes);
+    Episode {
+        video_id: video.id.clone(),
+        title: video.title().into(),
+        season: video.meta.season.or(title_season).or(season),
+        number: video.meta.episode.or(title_episode),
+    }
+}
+
+fn match_numbers(title: &str, episode_regexes: &[Regex]) -> (Option<u32>, Option<u32>) {
+    for regex in episode_regexes {
+        if let Some(captures) = regex.captures(title) {
+            let number = |name: &str| captures.name(name).and_then(|m| m.as_str().parse().ok());
+            return (number("season"), number("episode"));
+        }
+    }
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(title: &str, season: Option<u32>, number: Option<u32>) -> Episode {
+        Episode {
+            video_id: "6300000000001".into(),
+            title: title.into(),
+            season,
+            number,
+        }
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(
+            episode("Pilot", Some(1), Some(2)).display_name(),
+            "S01E02 Pilot"
+        );
+        assert_eq!(episode("Pilot", None, Some(2)).display_name(), "E02 Pilot");
+        assert_eq!(episode("Pilot", Some(1), None).display_name(), "Pilot");
+    }
+
+    #[test]
+    fn leading_codes_are_not_repeated() {
+        assert_eq!(
+            episode("S01E02 Pilot", Some(1), Some(2)).display_name(),
+            "S01E02 Pilot"
+        );
+        assert_eq!(
+            episode("s1e2 - Pilot", Some(1), Some(2)).display_name(),
+            "S01E02 Pilot"
+        );
+        assert_eq!(
+            episode("Episode 2: Pilot", None, Some(2)).display_name(),
+            "E02 Pilot"
+        );
+        assert_eq!(
+            episode("Ep. 2 Pilot", Some(1), Some(2)).display_name(),
+            "S01E02 Pilot"
+        );
+        // nothing left but the code
+        assert_eq!(episode("S01E02", Some(1), Some(2)).display_name(), "S01E02");
+        // codes elsewhere and words starting with E stay
+        assert_eq!(
+            episode("Pilot S01E02", Some(1), Some(2)).display_name(),
+            "S01E02 Pilot S01E02"
+        );
+        assert_eq!(
+            episode("Eagles 2024", None, Some(2)).display_name(),
+            "E02 Eagles 2024"
+        );
+        // without a number the title is used as it is
+        assert_eq!(
+            episode("S01E02 Pilot", Some(1), None).display_name(),
+            "S01E02 Pilot"
+        );
+    }
+}
@@ -0,0 +1,710 @@
+use crate::downloader::Downloader;
+use crate::err::{SError, SResult};
+use crate::quality::{QualityPolicy, has_video_codec};
+use crate::video_meta::Rendition;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, create_dir_all, remove_dir_all};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+pub const MASTER_PLAYLIST_NAME: &str = "master.m3u8";
+/// Per track segment checksums, kept after the segments are concatenated
+pub const SEGMENTS_SUFFIX: &str = ".segments.json";
+/// Per track segment downloads, removed once concatenated
+pub const PARTS_SUFFIX: &str = ".parts";
+/// Log line naming a finished track, the video track is written last
+pub const HLS_DESTINATION_PREFIX: &str = "[hls] Destination: ";
+/// Segment manifests are rewritten whole, resume re-checks the segments on disk anyway
+pub const MANIFEST_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Variant {
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+    pub audio_group: Option<String>,
+    pub url: Url,
+}
+
+/// An EXT-X-MEDIA audio track, muxed into the variant when it has no url
+pub struct AudioRendition {
+    pub group_id: String,
+    pub name: String,
+    pub default: bool,
+    pub url: Option<Url>,
+}
+
+pub struct MasterPlaylist {
+    pub variants: Vec<Variant>,
+    pub audio: Vec<AudioRendition>,
+}
+
+pub struct MediaPlaylist {
+    /// EXT-X-MAP, only fMP4 has one
+    pub init: Option<Url>,
+    pub segments: Vec<Segment>,
+    /// Without EXT-X-ENDLIST it is a live stream
+    pub ended: bool,
+    /// EXT-X-MEDIA-SEQUENCE, the number of the first segment. Live playlists slide forward
+    pub media_sequence: usize,
+    /// EXT-X-TARGETDURATION, how often a live playlist should be reloaded
+    pub target_duration: Option<f64>,
+}
+
+pub struct Segment {
+    pub url: Url,
+    pub duration: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SegmentRecord {
+    pub index: usize,
+    /// Without the query, CDN tokens change between playback requests
+    pub uri_path: String,
+    pub duration: f64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SegmentManifest {
+    pub init: Option<SegmentRecord>,
+    pub segments: Vec<SegmentRecord>,
+    /// Concatenated media file name, set once written
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// What [download_hls] wrote and picked
+pub struct HlsDownload {
+    /// The last track written
+    pub media_path: PathBuf,
+    pub chosen: Vec<Rendition>,
+    /// Every rendition of the master playlist
+    pub inventory: Vec<Rendition>,
+}
+
+impl Variant {
+    pub fn rendition(&self) -> Rendition {
+        Rendition {
+            source: "hls".into(),
+            width: self.resolution.map(|(width, _)| width),
+            height: self.resolution.map(|(_, height)| height),
+            bitrate: Some(self.bandwidth),
+            codecs: self.codecs.clone(),
+            audio_only: self.resolution.is_none()
+                && self.codecs.as_deref().is_some_and(|c| !has_video_codec(c)),
+            ..Rendition::default()
+        }
+    }
+}
+
+impl AudioRendition {
+    pub fn rendition(&self) -> Rendition {
+        Rendition {
+            source: "hls-audio".into(),
+            format_id: Some(self.name.clone()),
+            audio_only: true,
+            ..Rendition::default()
+        }
+    }
+}
+
+impl MasterPlaylist {
+    /// The default track of the variant's group, if it isn't muxed in
+    pub fn audio_for(&self, variant: &Variant) -> Option<&AudioRendition> {
+        let group = variant.audio_group.as_ref()?;
+        let mut candidates = self.audio.iter().filter(|a| &a.group_id == group);
+        let found = candidates
+            .clone()
+            .find(|a| a.default)
+            .or_else(|| candidates.next())?;
+        found.url.as_ref().map(|_| found)
+    }
+}
+
+pub fn is_master_playlist(text: &str) -> bool {
+    text.contains("#EXT-X-STREAM-INF")
+}
+
+pub fn parse_master(text: &str, base: &Url) -> SResult<MasterPlaylist> {
+    let mut variants = Vec::new();
+    let mut audio = Vec::new();
+    let mut pending: Option<HashMap<String, String>> = None;
+    for line in playlist_lines(text)? {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending = Some(parse_attributes(attrs));
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let attrs = parse_attributes(attrs);
+            if attrs.get("TYPE").map(String::as_str) != Some("AUDIO") {
+                continue;
+            }
+            audio.push(AudioRendition {
+                group_id: attrs.get("GROUP-ID").cloned().unwrap_or_default(),
+                name: attrs.get("NAME").cloned().unwrap_or_default(),
+                default: attrs.get("DEFAULT").is_some_and(|v| v == "YES"),
+                url: attrs.get("URI").map(|uri| join(base, uri)).transpose()?,
+            });
+        } else if !line.starts_with('#') {
+            let Some(attrs) = pending.take() else {
+                return Err(SError::hls(format!("uri without EXT-X-STREAM-INF {line}")));
+            };
+            variants.push(Variant {
+                bandwidth: attrs
+                    .get("BANDWIDTH")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").and_then(|v| {
+                    let (width, height) = v.split_once('x')?;
+                    Some((width.parse().ok()?, height.parse().ok()?))
+                }),
+                codecs: attrs.get("CODECS").cloned(),
+                audio_group: attrs.get("AUDIO").cloned(),
+                url: join(base, line)?,
+            });
+        }
+    }
+    Ok(MasterPlaylist { variants, audio })
+}
+
+pub fn parse_media(text: &str, base: &Url) -> SResult<MediaPlaylist> {
+    let mut init = None;
+    let mut segments = Vec::new();
+    let mut ended = false;
+    let mut media_sequence = 0;
+    let mut target_duration = None;
+    let mut duration = None;
+    for line in playlist_lines(text)? {
+        if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            media_sequence = value.trim().parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            target_duration = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default();
+            duration = Some(value.trim().parse().unwrap_or(0.0));
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            let attrs = parse_attributes(attrs);
+            if attrs.contains_key("BYTERANGE") {
+                return Err(SError::hls("EXT-X-MAP with BYTERANGE"));
+            }
+            let uri = attrs
+                .get("URI")
+                .ok_or_else(|| SError::hls("EXT-X-MAP without URI"))?;
+            init = Some(join(base, uri)?);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = parse_attributes(attrs);
+            let method = attrs.get("METHOD").map(String::as_str).unwrap_or("NONE");
+            if method != "NONE" {
+                return Err(SError::hls(format!("encrypted with {method}")));
+            }
+        } else if line.starts_with("#EXT-X-BYTERANGE") {
+            return Err(SError::hls("EXT-X-BYTERANGE segments"));
+        } else if line == "#EXT-X-ENDLIST" {
+            ended = true;
+        } else if !line.starts_with('#') {
+            segments.push(Segment {
+                url: join(base, line)?,
+                duration: duration.take().unwrap_or(0.0),
+            });
+        }
+    }
+    Ok(MediaPlaylist {
+        init,
+        segments,
+        ended,
+        media_sequence,
+        target_duration,
+    })
+}
+
+fn playlist_lines(text: &str) -> SResult<impl Iterator<Item = &str>> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(SError::hls("missing #EXTM3U header"));
+    }
+    Ok(lines)
+}
+
+/// KEY=VALUE,KEY="quoted, with commas"
+fn parse_attributes(input: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = input;
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let after = quoted.get(end + 1..).unwrap_or_default();
+            (&quoted[..end], after)
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        attrs.insert(key.trim().to_string(), value.to_string());
+        rest = after.strip_prefix(',').unwrap_or(after);
+    }
+    attrs
+}
+
+fn join(base: &Url, uri: &str) -> SResult<Url> {
+    base.join(uri)
+        .map_err(|e| SError::hls(format!("bad uri {uri}: {e}")))
+}
+
+/// Download one track of an ended media playlist into `<output_stem>.<ts|mp4>`.
+/// Checked segments from an earlier attempt are kept. None when `stop` said so.
+pub fn download_track(
+    downloader: &mut Downloader,
+    video_root: &Path,
+    track_name: &str,
+    output_stem: &str,
+    playlist_url: &Url,
+    stop: &dyn Fn() -> bool,
+    log: &mut File,
+) -> SResult<Option<PathBuf>> {
+    let playlist_raw = downloader.fetch_url(playlist_url.as_str())?;
+    let playlist_path = video_root.join(format!("{track_name}.m3u8"));
+    std::fs::write(&playlist_path, &playlist_raw).map_err(SError::io(&playlist_path))?;
+    let playlist = parse_media(&String::from_utf8_lossy(&playlist_raw), playlist_url)?;
+    if !playlist.ended {
+        return Err(SError::hls("live playlist without EXT-X-ENDLIST"));
+    }
+
+    let manifest_path = video_root.join(format!("{track_name}{SEGMENTS_SUFFIX}"));
+    let previous = SegmentManifest::load(&manifest_path)?;
+    let parts_root = video_root.join(format!("{track_name}{PARTS_SUFFIX}"));
+    create_dir_all(&parts_root).map_err(SError::io(&parts_root))?;
+
+    let mut manifest = SegmentManifest::default();
+    // init first, then segments in order
+    let mut part_paths = Vec::new();
+    if let Some(init_url) = &playlist.init {
+        let part_path = parts_root.join(format!("init.{}", url_ext(init_url, "mp4")));
+        let record = fetch_segment(
+            downloader,
+            init_url,
+            0,
+            0.0,
+            &part_path,
+            previous.init.as_ref(),
+        )?;
+        manifest.init = Some(record);
+        part_paths.push(part_path);
+    }
+    let total = playlist.segments.len();
+    let mut last_save = Instant::now();
+    for (index, segment) in playlist.segments.iter().enumerate() {
+        if stop() {
+            manifest.save(&manifest_path)?;
+            return Ok(None);
+        }
+        let part_path = parts_root.join(format!("{index:05}.{}", url_ext(&segment.url, "ts")));
+        let record = fetch_segment(
+            downloader,
+            &segment.url,
+            index,
+            segment.duration,
+            &part_path,
+            previous.segments.get(index),
+        )?;
+        manifest.segments.push(record);
+        part_paths.push(part_path);
+        if last_save.elapsed() >= MANIFEST_SAVE_INTERVAL {
+            manifest.save(&manifest_path)?;
+            last_save = Instant::now();
+        }
+        if (index + 1) % 50 == 0 || index + 1 == total {
+            writeln!(log, "[hls] {track_name} segment {}/{total}", index + 1)
+                .map_err(SError::io(video_root))?;
+        }
+    }
+    manifest.save(&manifest_path)?;
+
+    // fMP4 segments need their init section in front
+    let ext = if playlist.init.is_some() { "mp4" } else { "ts" };
+    let output_path = video_root.join(format!("{output_stem}.{ext}"));
+    let temp_path = video_root.join(format!("{output_stem}.{ext}.part"));
+    let mut output = File::create(&temp_path).map_err(SError::io(&temp_path))?;
+    for part_path in &part_paths {
+        let mut part = File::open(part_path).map_err(SError::io(part_path))?;
+        std::io::copy(&mut part, &mut output).map_err(SError::io(&temp_path))?;
+    }
+    output.flush().map_err(SError::io(&temp_path))?;
+    std::fs::rename(&temp_path, &output_path).map_err(SError::io(&output_path))?;
+    manifest.output = Some(format!("{output_stem}.{ext}"));
+    manifest.save(&manifest_path)?;
+    remove_dir_all(&parts_root).map_err(SError::io(&parts_root))?;
+    writeln!(log, "{HLS_DESTINATION_PREFIX}{}", output_path.display())
+        .map_err(SError::io(video_root))?;
+    info!(
+        "concatenated {total} segments into {}",
+        output_path.display()
+    );
+    Ok(Some(output_path))
+}
+
+/// Reuse the part on disk if it still matches its recorded checksum
+fn fetch_segment(
+    downloader: &mut Downloader,
+    url: &Url,
+    index: usize,
+    duration: f64,
+    part_path: &Path,
+    previous: Option<&SegmentRecord>,
+) -> SResult<SegmentRecord> {
+    let uri_path = url.path().to_string();
+    if let Some(previous) = previous.filter(|p| p.uri_path == uri_path)
+        && part_path.exists()
+    {
+        let existing = std::fs::read(part_path).map_err(SError::io(part_path))?;
+        if sha256_hex(&existing) == previous.sha256 {
+            debug!("resume, keeping segment {index}");
+            return Ok(previous.clone());
+        }
+        warn!("checksum mismatch, refetching {}", part_path.display());
+    }
+
+    let body = downloader.fetch_url(url.as_str())?;
+    std::fs::write(part_path, &body).map_err(SError::io(part_path))?;
+    Ok(SegmentRecord {
+        index,
+        uri_path,
+        duration,
+        size: body.len() as u64,
+        sha256: sha256_hex(&body),
+    })
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn url_ext<'u>(url: &'u Url, fallback: &'u str) -> &'u str {
+    url.path()
+        .rsplit_once('/')
+        .and_then(|(_, name)| name.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty() && ext.len() <= 4)
+        .unwrap_or(fallback)
+}
+
+impl SegmentManifest {
+    /// Size of the concatenated output
+    pub fn content_length(&self) -> u64 {
+        self.init.iter().chain(&self.segments).map(|s| s.size).sum()
+    }
+
+    pub fn load(path: &Path) -> SResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let mut raw = std::fs::read(path).map_err(SError::io(path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(path))
+    }
+
+    pub fn save(&self, path: &Path) -> SResult<()> {
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(path))?;
+        std::fs::write(path, raw).map_err(SError::io(path))
+    }
+}
+
+/// The media playlist of the last rendition the policy picks and its url, following a master
+/// playlist
+pub fn resolve_media_playlist(
+    downloader: &mut Downloader,
+    url: &Url,
+    policy: &QualityPolicy,
+) -> SResult<(Url, MediaPlaylist)> {
+    let raw = downloader.fetch_url(url.as_str())?;
+    let text = String::from_utf8_lossy(&raw);
+    if !is_master_playlist(&text) {
+        return Ok((url.clone(), parse_media(&text, url)?));
+    }
+    let master = parse_master(&text, url)?;
+    let inventory: Vec<Rendition> = master.variants.iter().map(Variant::rendition).collect();
+    let Some(index) = policy.select(&inventory).last().copied() else {
+        return Err(SError::hls("master playlist without variants"));
+    };
+    let media_url = master.variants[index].url.clone();
+    let media_raw = downloader.fetch_url(media_url.as_str())?;
+    let media = parse_media(&String::from_utf8_lossy(&media_raw), &media_url)?;
+    Ok((media_url, media))
+}
+
+/// Pick renditions of the master playlist by the policy and download them, plus their separate
+/// audio tracks if any. The playlists are saved next to the media. None when `stop` said so.
+pub fn download_hls(
+    downloader: &mut Downloader,
+    video_root: &Path,
+    video_id: &str,
+    master_url: &Url,
+    policy: &QualityPolicy,
+    stop: &dyn Fn() -> bool,
+    log: &mut File,
+) -> SResult<Option<HlsDownload>> {
+    let master_raw = downloader.fetch_url(master_url.as_str())?;
+    let master_path = video_root.join(MASTER_PLAYLIST_NAME);
+    std::fs::write(&master_path, &master_raw).map_err(SError::io(&master_path))?;
+    let master_text = String::from_utf8_lossy(&master_raw);
+    if !is_master_playlist(&master_text) {
+        // already a single rendition
+        let only = Rendition {
+            source: "hls".into(),
+            ..Rendition::default()
+        };
+        let media_path = download_track(
+            downloader, video_root, "video", video_id, master_url, stop, log,
+        )?;
+        return Ok(media_path.map(|media_path| HlsDownload {
+            media_path,
+            chosen: vec![only.clone()],
+            inventory: vec![only],
+        }));
+    }
+
+    let master = parse_master(&master_text, master_url)?;
+    // variants first, so their indexes match
+    let mut urls: Vec<&Url> = master.variants.iter().map(|v| &v.url).collect();
+    let mut inventory: Vec<Rendition> = master.variants.iter().map(Variant::rendition).collect();
+    for audio in &master.audio {
+        if let Some(url) = &audio.url {
+            urls.push(url);
+            inventory.push(audio.rendition());
+        }
+    }
+    let selected = policy.select(&inventory);
+    if selected.is_empty() {
+        return Err(SError::hls("master playlist without variants"));
+    }
+    let labels: Vec<String> = selected.iter().map(|i| inventory[*i].label()).collect();
+    let description = format!(
+        "[hls] {} selected {} of {} renditions",
+        policy.describe(),
+        labels.join(","),
+        inventory.len(),
+    );
+    info!("{video_id} {description}");
+    writeln!(log, "{description}").map_err(SError::io(video_root))?;
+
+    let keep_all = selected.len() > 1;
+    let mut chosen = Vec::new();
+    let mut audio_groups_done = HashSet::new();
+    let mut media_path = None;
+    for index in selected {
+        let rendition = &inventory[index];
+        // a variant may point to a separate audio track
+        if let Some(variant) = master.variants.get(index)
+            && let Some(audio) = master.audio_for(variant)
+            && audio_groups_done.insert(audio.group_id.clone())
+        {
+            warn!(
+                "{video_id} has separate audio track {}, not muxed",
+                audio.name
+            );
+            let audio_url = audio.url.as_ref().unwrap();
+            // one track per group, the label of every audio rendition is just "audio"
+            let (audio_track, audio_stem) = if keep_all {
+                let group: String = audio
+                    .group_id
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_alphanumeric() || c == '-' {
+                            c
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect();
+                (
+                    format!("audio-{group}"),
+                    format!("{video_id}.audio-{group}"),
+                )
+            } else {
+                ("audio".to_string(), format!("{video_id}.audio"))
+            };
+            if download_track(
+                downloader,
+                video_root,
+                &audio_track,
+                &audio_stem,
+                audio_url,
+                stop,
+                log,
+            )?
+            .is_none()
+            {
+                return Ok(None);
+            }
+            chosen.push(audio.rendition());
+        }
+        let (track_name, output_stem) = if keep_all {
+            let label = rendition.label();
+            (format!("video-{label}"), format!("{video_id}.{label}"))
+        } else {
+            ("video".to_string(), video_id.to_string())
+        };
+        let Some(path) = download_track(
+            downloader,
+            video_root,
+            &track_name,
+            &output_stem,
+            urls[index],
+            stop,
+            log,
+        )?
+        else {
+            return Ok(None);
+        };
+        chosen.push(rendition.clone());
+        media_path = Some(path);
+    }
+    Ok(Some(HlsDownload {
+        media_path: media_path.unwrap(),
+        chosen,
+        inventory,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example.com/v1/hls/master.m3u8?token=abc").unwrap()
+    }
+
+    fn media_error(text: &str) -> String {
+        match parse_media(text, &base()) {
+            Ok(_) => panic!("parsed {text}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let attrs = parse_attributes(
+            r#"BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2",RESOLUTION=1280x720,AUDIO="aac""#,
+        );
+        assert_eq!(attrs["BANDWIDTH"], "1280000");
+        assert_eq!(attrs["CODECS"], "avc1.4d401f,mp4a.40.2");
+        assert_eq!(attrs["RESOLUTION"], "1280x720");
+        assert_eq!(attrs["AUDIO"], "aac");
+        assert_eq!(attrs.len(), 4);
+        assert!(parse_attributes("").is_empty());
+    }
+
+    #[test]
+    fn master_playlist() {
+        let text = r#"#EXTM3U
+#EXT-X-VERSION:4
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac-lo",NAME="English",DEFAULT=NO,URI="audio/lo.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac-lo",NAME="English main",DEFAULT=YES,URI="audio/lo-main.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="muxed",NAME="English",DEFAULT=YES
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="English",URI="subs/en.m3u8"
+
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS="avc1.4d401e,mp4a.40.2",AUDIO="aac-lo"
+360p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,RESOLUTION=1280x720,AUDIO="muxed"
+https://other.example.com/720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS="mp4a.40.2"
+audio-only.m3u8
+"#;
+        assert!(is_master_playlist(text));
+        let master = parse_master(text, &base()).unwrap();
+        assert_eq!(master.audio.len(), 3);
+        assert_eq!(master.variants.len(), 3);
+
+        let low = &master.variants[0];
+        assert_eq!(low.bandwidth, 800000);
+        assert_eq!(low.resolution, Some((640, 360)));
+        assert_eq!(low.url.as_str(), "https://cdn.example.com/v1/hls/360p.m3u8");
+        let audio = master.audio_for(low).unwrap();
+        assert_eq!(audio.name, "English main");
+        assert_eq!(
+            audio.url.as_ref().unwrap().as_str(),
+            "https://cdn.example.com/v1/hls/audio/lo-main.m3u8"
+        );
+
+        let muxed = &master.variants[1];
+        assert_eq!(muxed.url.as_str(), "https://other.example.com/720p.m3u8");
+        assert!(master.audio_for(muxed).is_none());
+        assert!(!muxed.rendition().audio_only);
+
+        let audio_only = &master.variants[2];
+        assert_eq!(audio_only.resolution, None);
+        assert!(audio_only.rendition().audio_only);
+    }
+
+    #[test]
+    fn master_without_stream_inf() {
+        let text = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\na.m3u8\nb.m3u8\n";
+        assert!(parse_master(text, &base()).is_err());
+        assert!(parse_master("a.m3u8\n", &base()).is_err());
+    }
+
+    #[test]
+    fn media_playlist() {
+        let text = "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:41
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:6.006,
+seg41.m4s?token=a
+#EXTINF:5.5,title
+/other/seg42.m4s
+#EXT-X-ENDLIST
+";
+        assert!(!is_master_playlist(text));
+        let media = parse_media(text, &base()).unwrap();
+        assert_eq!(
+            media.init.unwrap().as_str(),
+            "https://cdn.example.com/v1/hls/init.mp4"
+        );
+        assert_eq!(media.media_sequence, 41);
+        assert_eq!(media.target_duration, Some(6.0));
+        assert!(media.ended);
+        assert_eq!(media.segments.len(), 2);
+        assert_eq!(
+            media.segments[0].url.as_str(),
+            "https://cdn.example.com/v1/hls/seg41.m4s?token=a"
+        );
+        assert_eq!(media.segments[0].duration, 6.006);
+        assert_eq!(
+            media.segments[1].url.as_str(),
+            "https://cdn.example.com/other/seg42.m4s"
+        );
+        assert_eq!(media.segments[1].duration, 5.5);
+    }
+
+    #[test]
+    fn live_media_playlist() {
+        let media = parse_media("#EXTM3U\n#EXTINF:2,\na.ts\n", &base()).unwrap();
+        assert!(!media.ended);
+        assert!(media.init.is_none());
+        assert_eq!(media.media_sequence, 0);
+        assert_eq!(media.target_duration, None);
+    }
+
+    #[test]
+    fn unsupported_media_playlists() {
+        assert!(
+            media_error("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"k\"\n")
+                .contains("encrypted with AES-128")
+        );
+        assert!(
+            media_error("#EXTM3U\n#EXTINF:2,\n#EXT-X-BYTERANGE:1000@0\na.ts\n")
+                .contains("EXT-X-BYTERANGE segments")
+        );
+        assert!(
+            media_error("#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"\n")
+                .contains("EXT-X-MAP with BYTERANGE")
+        );
+        assert!(
+            media_error("#EXTM3U\n#EXT-X-MAP:BYTERANGE-LESS\n").contains("EXT-X-MAP without URI")
+        );
+        assert!(media_error("#EXTINF:2,\na.ts\n").contains("missing #EXTM3U header"));
+    }
+}
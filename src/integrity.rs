@@ -0,0 +1,357 @@
+use crate::crawl::CrawlResult;
+use crate::downloader::{DownType, VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::hls::PARTS_SUFFIX;
+use crate::video_dl::is_partial_name;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs::{File, create_dir_all, read_dir};
+use std::path::{Path, PathBuf};
+use strum::VariantArray;
+use tracing::{info, warn};
+
+pub const INTEGRITY_MANIFEST_NAME: &str = "integrity-manifest.json";
+/// Same hashes for `sha256sum -c`, run from the output root
+pub const SHA256SUMS_NAME: &str = "sha256sums.txt";
+pub const AUDIT_REPORT_NAME: &str = "audit-report.md";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub sha256: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// When the hash was taken
+    pub recorded: DateTime<Utc>,
+}
+
+/// Hash of every file under vid-dl and the response caches, paths relative to the output root
+#[derive(Default, Serialize, Deserialize)]
+pub struct IntegrityManifest {
+    files: BTreeMap<String, FileRecord>,
+}
+
+/// What [IntegrityManifest::audit] found
+#[derive(Default)]
+pub struct Audit {
+    pub verified: usize,
+    /// Contents changed but size and modification time didn't
+    pub bit_rot: Vec<String>,
+    /// Rewritten since recorded, e.g. a re-download. Run checksum to accept
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    /// On disk but never recorded
+    pub unexpected: Vec<String>,
+}
+
+impl IntegrityManifest {
+    pub fn load() -> SResult<Self> {
+        let manifest_path = db_path([INTEGRITY_MANIFEST_NAME]);
+        if !manifest_path.exists() {
+            return Ok(Self::default());
+        }
+        let mut raw = std::fs::read(&manifest_path).map_err(SError::io(&manifest_path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(&manifest_path))
+    }
+
+    /// Write then rename, the manifest is the one file we can't afford to lose half of
+    pub fn save(&self) -> SResult<()> {
+        let manifest_path = db_path([INTEGRITY_MANIFEST_NAME]);
+        let temp_path = manifest_path.with_extension("json.tmp");
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&manifest_path))?;
+        std::fs::write(&temp_path, raw).map_err(SError::io(&temp_path))?;
+        std::fs::rename(&temp_path, &manifest_path).map_err(SError::io(&manifest_path))?;
+
+        let sums_path = db_path([SHA256SUMS_NAME]);
+        let mut sums = String::new();
+        for (name, record) in &self.files {
+            writeln!(sums, "{}  {name}", record.sha256).unwrap();
+        }
+        std::fs::write(&sums_path, sums).map_err(SError::io(&sums_path))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FileRecord> {
+        self.files.get(name)
+    }
+
+    /// Hash new files and ones rewritten since, keep records of missing ones unless told to forget.
+    /// Gives the number of files hashed
+    pub fn update(&mut self, forget_missing: bool, dry_run: bool) -> SResult<usize> {
+        let on_disk = archive_files()?;
+        let mut hashed = 0;
+        for name in &on_disk {
+            let file_path = db_path([name]);
+            let (size, modified) = stat(&file_path)?;
+            if let Some(record) = self.files.get(name)
+                && record.size == size
+                && record.modified == modified
+            {
+                continue;
+            }
+            if dry_run {
+                println!("dry-run: would hash {name}");
+                continue;
+            }
+            let record = FileRecord {
+                sha256: sha256_file(&file_path)?,
+                size,
+                modified,
+                recorded: Utc::now(),
+            };
+            self.files.insert(name.clone(), record);
+            hashed += 1;
+        }
+
+        let missing: Vec<String> = self
+            .files
+            .keys()
+            .filter(|name| !on_disk.contains(*name))
+            .cloned()
+            .collect();
+        if !missing.is_empty() && forget_missing {
+            info!("forgetting {} missing files", missing.len());
+            for name in &missing {
+                self.files.remove(name);
+            }
+        } else if !missing.is_empty() {
+            warn!(
+                "{} recorded files are missing, see audit or use --forget-missing",
+                missing.len()
+            );
+        }
+        Ok(hashed)
+    }
+
+    /// Hash everything again and compare
+    pub fn audit(&self) -> SResult<Audit> {
+        let on_disk = archive_files()?;
+        let mut audit = Audit::default();
+        for (name, record) in &self.files {
+            if !on_disk.contains(name) {
+                audit.missing.push(name.clone());
+                continue;
+            }
+            let file_path = db_path([name]);
+            let (size, modified) = stat(&file_path)?;
+            if sha256_file(&file_path)? == record.sha256 {
+                audit.verified += 1;
+            } else if size == record.size && modified == record.modified {
+                warn!("bit rot in {name}");
+                audit.bit_rot.push(name.clone());
+            } else {
+                audit.modified.push(name.clone());
+            }
+        }
+        audit.unexpected = on_disk
+            .into_iter()
+            .filter(|name| !self.files.contains_key(name))
+            .collect();
+        Ok(audit)
+    }
+}
+
+impl Audit {
+    pub fn to_markdown(&self) -> String {
+        let mut md = "# Archive audit\n\n".to_string();
+        writeln!(md, "{} files verified\n", self.verified).unwrap();
+        for (heading, names) in [
+            ("Bit rot, restore from a copy", &self.bit_rot),
+            ("Missing", &self.missing),
+            ("Modified since recorded", &self.modified),
+            ("Unexpected, never recorded", &self.unexpected),
+        ] {
+            writeln!(md, "## {heading} ({})\n", names.len()).unwrap();
+            for name in names {
+                writeln!(md, "- `{name}`").unwrap();
+            }
+            md.push('\n');
+        }
+        md
+    }
+
+    pub fn write(&self) -> SResult<()> {
+        let report_path = db_path([AUDIT_REPORT_NAME]);
+        std::fs::write(&report_path, self.to_markdown()).map_err(SError::io(&report_path))?;
+        info!("wrote audit report to {}", report_path.display());
+        Ok(())
+    }
+}
+
+/// Relative to the output root, without downloads still in progress
+fn archive_files() -> SResult<BTreeSet<String>> {
+    let mut roots = vec![VIDEO_DL_NAME.to_string()];
+    roots.extend(DownType::VARIANTS.iter().map(|d| d.safe_name()));
+    let mut files = BTreeSet::new();
+    for root in roots {
+        if db_path([&root]).exists() {
+            walk_files(&PathBuf::from(root), &mut files)?;
+        }
+    }
+    Ok(files)
+}
+
+fn walk_files(relative: &Path, files: &mut BTreeSet<String>) -> SResult<()> {
+    let dir = db_path([&relative.to_string_lossy()]);
+    for entry in read_dir(&dir).map_err(SError::io(&dir))? {
+        let entry = entry.map_err(SError::io(&dir))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(PARTS_SUFFIX) || is_partial_name(&name) {
+            continue;
+        }
+        let child = relative.join(&name);
+        if entry.file_type().map_err(SError::io(&dir))?.is_dir() {
+            walk_files(&child, files)?;
+        } else {
+            // always / separated, like sha256sum expects
+            files.insert(
+                child
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn stat(file_path: &Path) -> SResult<(u64, DateTime<Utc>)> {
+    let metadata = std::fs::metadata(file_path).map_err(SError::io(file_path))?;
+    let modified = metadata.modified().map_err(SError::io(file_path))?;
+    Ok((metadata.len(), modified.into()))
+}
+
+/// Streamed, media files don't fit in memory
+pub fn sha256_file(file_path: &Path) -> SResult<String> {
+    let mut file = File::open(file_path).map_err(SError::io(file_path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(SError::io(file_path))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A BagIt 1.0 bag of the video directories under `bag_root/data/<id>/`, hard linked when possible.
+/// Refuses files the manifest shows have rotted, before anything is written
+pub fn export_bag(
+    bag_root: &Path,
+    video_ids: &[String],
+    domain: &str,
+    crawl: Option<&CrawlResult>,
+    dry_run: bool,
+) -> SResult<()> {
+    if bag_root.exists() {
+        return Err(SError::bag(format!(
+            "{} already exists",
+            bag_root.display()
+        )));
+    }
+    let manifest = IntegrityManifest::load()?;
+    let archived = archive_files()?;
+
+    let mut payload = Vec::new();
+    for video_id in video_ids {
+        let prefix = format!("{VIDEO_DL_NAME}/{video_id}/");
+        let before = payload.len();
+        payload.extend(archived.iter().filter(|name| name.starts_with(&prefix)));
+        if payload.len() == before {
+            return Err(SError::bag(format!("nothing downloaded for {video_id}")));
+        }
+    }
+    if dry_run {
+        println!(
+            "dry-run: would export {} files of {} videos to {}",
+            payload.len(),
+            video_ids.len(),
+            bag_root.display()
+        );
+        return Ok(());
+    }
+
+    let mut payload_manifest = String::new();
+    let mut total_size = 0;
+    for name in &payload {
+        let source_path = db_path([name.as_str()]);
+        let bag_name = format!("data/{}", &name[VIDEO_DL_NAME.len() + 1..]);
+        let (size, modified) = stat(&source_path)?;
+        let sha256 = sha256_file(&source_path)?;
+        if let Some(record) = manifest.get(name)
+            && record.size == size
+            && record.modified == modified
+            && record.sha256 != sha256
+        {
+            return Err(SError::bag(format!(
+                "bit rot in {name}, restore it before exporting"
+            )));
+        }
+        writeln!(payload_manifest, "{sha256}  {bag_name}").unwrap();
+        total_size += size;
+    }
+    for name in &payload {
+        let source_path = db_path([name.as_str()]);
+        let target_path = bag_root.join(format!("data/{}", &name[VIDEO_DL_NAME.len() + 1..]));
+        create_dir_all(target_path.parent().unwrap()).map_err(SError::io(&target_path))?;
+        if std::fs::hard_link(&source_path, &target_path).is_err() {
+            std::fs::copy(&source_path, &target_path).map_err(SError::io(&target_path))?;
+        }
+    }
+
+    let mut bag_info = String::new();
+    writeln!(bag_info, "Source-Organization: {domain}").unwrap();
+    writeln!(bag_info, "Bagging-Date: {}", Utc::now().format("%Y-%m-%d")).unwrap();
+    writeln!(
+        bag_info,
+        "Bag-Software-Agent: {} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(bag_info, "Payload-Oxum: {total_size}.{}", payload.len()).unwrap();
+    for video_id in video_ids {
+        writeln!(bag_info, "External-Identifier: {video_id}").unwrap();
+        let node = crawl.and_then(|crawl| crawl.videos().find(|v| &v.id == video_id));
+        let Some(node) = node else {
+            warn!("{video_id} not in the latest crawl, no description");
+            continue;
+        };
+        let mut description = format!("{video_id}: {}", node.title());
+        if let (Some(season), Some(episode)) = (node.meta.season, node.meta.episode) {
+            write!(description, " S{season:02}E{episode:02}").unwrap();
+        }
+        if let Some(text) = &node.meta.description {
+            // one line per label
+            let text: Vec<&str> = text.split_whitespace().collect();
+            write!(description, ". {}", text.join(" ")).unwrap();
+        }
+        writeln!(bag_info, "External-Description: {description}").unwrap();
+    }
+
+    let tag_files = [
+        (
+            "bagit.txt",
+            "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n".to_string(),
+        ),
+        ("bag-info.txt", bag_info),
+        ("manifest-sha256.txt", payload_manifest),
+    ];
+    let mut tag_manifest = String::new();
+    for (name, content) in &tag_files {
+        let tag_path = bag_root.join(name);
+        std::fs::write(&tag_path, content).map_err(SError::io(&tag_path))?;
+        writeln!(
+            tag_manifest,
+            "{:x}  {name}",
+            Sha256::digest(content.as_bytes())
+        )
+        .unwrap();
+    }
+    let tag_manifest_path = bag_root.join("tagmanifest-sha256.txt");
+    std::fs::write(&tag_manifest_path, tag_manifest).map_err(SError::io(&tag_manifest_path))?;
+    info!(
+        "exported {} files of {} videos to {}",
+        payload.len(),
+        video_ids.len(),
+        bag_root.display()
+    );
+    Ok(())
+}
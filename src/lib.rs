@@ -2,34 +2,78 @@
 #![feature(error_generic_member_access)]
 #![feature(iterator_try_collect)]
 
-use crate::downloader::{
-    BROWSE_NAME, DownType, Downloader, EXTRACTION_DB_ROOT, VIDEO_DL_NAME, path,
-};
+use crate::backend::DownloaderBackend;
+use crate::browse::BrowseTree;
+use crate::changelog::Changelog;
+use crate::cli::{Cli, Command, DownloadArgs, GlobalArgs, LogFormat, MarkState};
+use crate::crawl::{CrawlNode, CrawlResult, clear_crawl_state, crawl_catalog, pending_frontier};
+use crate::download::{DownloadOptions, run_downloads};
+use crate::downloader::{DownType, Downloader, VIDEO_DL_NAME, db_path, set_output_root};
 use crate::err::{SError, SResult, pretty_panic};
-use crate::extractor::{
-    ExtractedThing, ThingType, extract_collections_from_root, extract_original_id,
-    extract_things_from_collection,
-};
+use crate::extractor::ThingType;
+use crate::failure::{FAILURE_REPORT_NAME, failure_report, write_failure_report};
 use crate::global_config::GlobalConfig;
-use simd_json::prelude::{ArrayTrait, ValueObjectAccessAsScalar};
+use crate::hierarchy::Hierarchy;
+use crate::integrity::{IntegrityManifest, export_bag};
+use crate::live::{capture_live, link_recordings};
+use crate::meta_history::{
+    MetadataHistory, backfill, changes_markdown, crawl_entries, video_history_markdown,
+};
+use crate::probe::{verify_media, verify_report, write_verify_report};
+use crate::recheck::{VodFingerprint, VodVersion};
+use crate::version_diff::{VersionSource, diff_versions};
+use crate::video_dl::{
+    DownloadJob, DownloadStates, VideoState, download_job, list_video_ids, load_youtube_dl,
+};
+use crate::video_meta::VideoMeta;
+use chrono::{TimeDelta, Utc};
+use clap::Parser;
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::{create_dir, read_dir};
+use std::fs::create_dir;
 use std::process::ExitCode;
+use std::time::Duration;
 use tracing::{info, trace, warn};
 use tracing_subscriber::fmt::Layer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
+mod backend;
+mod brightcove;
+mod browse;
+mod changelog;
+mod cli;
+mod crawl;
+mod download;
 mod downloader;
 mod err;
 mod extractor;
+mod failure;
 mod global_config;
+mod graph;
+mod hierarchy;
+mod hls;
+mod integrity;
+mod live;
+mod meta_history;
+mod naming;
+mod probe;
+mod quality;
+mod recheck;
+mod sanitize;
+mod subtitles;
 mod utils;
+mod version_diff;
+mod video_dl;
+mod video_meta;
+
+const YTDL_SCRIPT_NAME: &str = "ytdl-scrape.sh";
 
 pub fn start_scraper() -> ExitCode {
-    init_logging();
-    if let Err(e) = _start_scraper() {
+    let cli = Cli::parse();
+    init_logging(cli.global.log_format);
+    if let Err(e) = _start_scraper(cli) {
         pretty_panic(e);
         ExitCode::FAILURE
     } else {
@@ -37,218 +81,573 @@ pub fn start_scraper() -> ExitCode {
     }
 }
 
-fn _start_scraper() -> SResult<()> {
-    let global_config = GlobalConfig::load()?;
-    let mut downloader = Downloader::init(&global_config);
-    DownType::mkdirs();
-
-    let mut all_videos: Vec<ExtractedThing> = Vec::new();
-    let mut seen_ids = Vec::new();
-
-    let mut spider = vec![ExtractedThing {
-        next_type: ThingType::Page,
-        next_id: load_root_collection_id(&mut downloader)?,
-        title: "from_root".into(),
-    }];
-    while let Some(cur_thing) = spider.pop() {
-        if seen_ids.contains(&cur_thing.next_id) {
-            // Apparently videos exist in multiple collections
-            assert_eq!(cur_thing.next_type, ThingType::Video);
-
-            warn!("skipping seen id {}", cur_thing.next_id);
-            continue;
+fn _start_scraper(cli: Cli) -> SResult<()> {
+    let global_args = cli.global;
+    set_output_root(global_args.output_root.clone());
+    let global_config = GlobalConfig::load(&global_args.config)?;
+    DownType::mkdirs(global_args.dry_run);
+
+    match cli.command {
+        Command::Crawl { resume } => run_crawl(&global_args, &global_config, resume),
+        Command::PlanDownloads => plan_downloads(&global_args, &global_config),
+        Command::Download { download } => {
+            run_download(&global_args, &global_config, &download_options(&download))
         }
-        seen_ids.push(cur_thing.next_id.clone());
-
-        match cur_thing.next_type {
-            ThingType::Page => {
-                let collections = load_collections_from_page(&mut downloader, &cur_thing.next_id)?;
-                spider.extend(collections);
+        Command::Recheck {
+            min_age_days,
+            limit,
+            download,
+        } => run_recheck(
+            &global_args,
+            &global_config,
+            TimeDelta::days(min_age_days as i64),
+            limit,
+            &download_options(&download),
+        ),
+        Command::DiffVersions { old, new } => {
+            let (old, new) = (VersionSource::parse(&old)?, VersionSource::parse(&new)?);
+            // archived versions need no network, nor the proxy
+            let needs_network = [&old, &new]
+                .iter()
+                .any(|source| matches!(source, VersionSource::Playlist(_)));
+            let mut downloader = Downloader::init(
+                &global_config,
+                global_args.offline || !needs_network,
+                global_args.dry_run,
+            );
+            let diff = diff_versions(
+                &global_config.downloader,
+                &mut downloader,
+                &global_config.quality,
+                &old,
+                &new,
+            )?;
+            println!("{}", diff.to_edl());
+            if !global_args.dry_run {
+                diff.write()?;
             }
-            ThingType::Collection => {
-                let nexts = load_collection(&mut downloader, &cur_thing.next_id)?;
-                spider.extend(nexts)
+            Ok(())
+        }
+        Command::Live { poll_secs } => capture_live(
+            &global_config,
+            Duration::from_secs(poll_secs),
+            global_args.offline,
+            global_args.dry_run,
+        ),
+        Command::Browse { rename } => run_browse(&global_args, &global_config, rename),
+        Command::Verify {
+            probe,
+            duration_tolerance_secs,
+        } => run_verify(
+            &global_args,
+            &global_config,
+            probe.then_some(duration_tolerance_secs),
+        ),
+        Command::Checksum { forget_missing } => {
+            let mut manifest = IntegrityManifest::load()?;
+            let hashed = manifest.update(forget_missing, global_args.dry_run)?;
+            if !global_args.dry_run {
+                manifest.save()?;
+                info!("hashed {hashed} new or rewritten files");
             }
-            ThingType::Video => {
-                all_videos.push(cur_thing);
+            Ok(())
+        }
+        Command::Audit => {
+            let audit = IntegrityManifest::load()?.audit()?;
+            println!("{}", audit.to_markdown());
+            if !global_args.dry_run {
+                audit.write()?;
             }
+            Ok(())
+        }
+        Command::ExportBag { out, video_ids } => export_bag(
+            &out,
+            &video_ids,
+            &global_config.domain,
+            CrawlResult::load_latest().ok().as_ref(),
+            global_args.dry_run,
+        ),
+        Command::History {
+            video_id,
+            since,
+            until,
+            backfill: do_backfill,
+        } => {
+            let mut history = MetadataHistory::load()?;
+            if do_backfill {
+                backfill(&global_config.downloader, &mut history, global_args.dry_run)?;
+            }
+            let report = match video_id {
+                Some(video_id) => video_history_markdown(&video_id, &history.of_video(&video_id)),
+                None => changes_markdown(since, until, &history.changes_between(since, until)),
+            };
+            println!("{report}");
+            Ok(())
+        }
+        Command::Status => run_status(&global_config),
+        Command::Failures => {
+            let crawl = CrawlResult::load_latest().ok();
+            let states = DownloadStates::load()?;
+            let report = if global_args.dry_run {
+                println!("dry-run: would write {FAILURE_REPORT_NAME}");
+                failure_report(&states, crawl.as_ref())
+            } else {
+                write_failure_report(&states, crawl.as_ref())?
+            };
+            println!("{report}");
+            Ok(())
+        }
+        Command::Mark {
+            video_id,
+            state,
+            reason,
+        } => run_mark(&global_args, &video_id, state, reason),
+        Command::Changelog { previous, current } => {
+            let snapshots = CrawlResult::list_snapshots()?;
+            let mut latest = snapshots.iter().rev();
+            let current = match current.as_ref().or_else(|| latest.next()) {
+                Some(current) => CrawlResult::load_snapshot(current)?,
+                None => return Err(SError::no_snapshot()),
+            };
+            let previous = match previous.as_ref().or_else(|| latest.next()) {
+                Some(previous) => CrawlResult::load_snapshot(previous)?,
+                None => return Err(SError::no_snapshot()),
+            };
+            let changelog = Changelog::compare(
+                &previous,
+                &current,
+                &global_config.downloader,
+                &DownloadStates::load()?,
+            )?;
+            if global_args.dry_run {
+                println!("dry-run: would write the changelog below");
+            } else {
+                changelog.write()?;
+            }
+            println!("{}", changelog.to_markdown());
+            Ok(())
         }
     }
-    info!("extracted {} videos", all_videos.len());
+}
+
+fn run_crawl(global_args: &GlobalArgs, global_config: &GlobalConfig, resume: bool) -> SResult<()> {
+    let mut downloader = Downloader::init(global_config, global_args.offline, global_args.dry_run);
+    let crawl = crawl_catalog(&mut downloader, resume, global_args.dry_run)?;
+    if global_args.dry_run {
+        println!(
+            "dry-run: would save a snapshot of {} videos and write the changelog, hierarchy, and graph",
+            crawl.videos().count()
+        );
+        return Ok(());
+    }
+    write_changelog(global_config, &crawl)?;
+    clear_crawl_state()?;
+    let recorded = MetadataHistory::load()?.record(crawl_entries(&crawl), false)?;
+    info!("recorded {recorded} metadata history entries");
+    let hierarchy = Hierarchy::build(&crawl, &global_config.episode_regexes);
+    hierarchy.write()?;
+    crawl.graph.write(&crawl)?;
+    link_recordings(&crawl)?;
+    Ok(())
+}
 
-    all_videos.retain(|v| !global_config.missing_videos.contains(&v.next_id));
+/// Videos of the latest crawl minus the ones known to be broken
+fn wanted_videos<'c>(global_config: &GlobalConfig, crawl: &'c CrawlResult) -> Vec<&'c CrawlNode> {
+    let mut all_videos: Vec<&CrawlNode> = crawl.videos().collect();
+    all_videos.retain(|v| !global_config.missing_videos.contains(&v.id));
+    all_videos
+}
 
-    let mut ytdl_commands: Vec<String> = vec!["#!/bin/bash".into(), "set -eux".into()];
-    for video_id in &all_videos {
-        if let Some(commands) = load_youtube_dl(&global_config, video_id)? {
-            ytdl_commands.extend(commands);
+/// Videos of the latest crawl not yet downloaded
+fn plan_jobs(global_args: &GlobalArgs, global_config: &GlobalConfig) -> SResult<Vec<DownloadJob>> {
+    let crawl = CrawlResult::load_latest()?;
+    let states = DownloadStates::load()?;
+    let mut jobs = Vec::new();
+    for video_id in wanted_videos(global_config, &crawl) {
+        if let Some(job) = load_youtube_dl(global_config, &states, video_id, global_args.dry_run)? {
+            if global_args.dry_run {
+                println!("dry-run: would download {} {}", job.video_id, job.title);
+            }
+            jobs.push(job);
         }
     }
-    let ytdl_script_path = path([EXTRACTION_DB_ROOT, "ytdl-scrape.sh"]);
+    Ok(jobs)
+}
+
+/// Export the plan as a script to run by hand
+fn plan_downloads(global_args: &GlobalArgs, global_config: &GlobalConfig) -> SResult<()> {
+    if matches!(global_config.downloader, DownloaderBackend::Native) {
+        warn!("the native downloader runs in process, use the download command instead");
+        return Ok(());
+    }
+    let jobs = plan_jobs(global_args, global_config)?;
+    let ytdl_script_path = db_path([YTDL_SCRIPT_NAME]);
+    if global_args.dry_run {
+        println!(
+            "dry-run: would write {} downloads to {}",
+            jobs.len(),
+            ytdl_script_path.display()
+        );
+        return Ok(());
+    }
+
+    // pipefail so a failed download behind tee never gets a done marker
+    let mut ytdl_commands: Vec<String> = vec!["#!/bin/bash".into(), "set -euxo pipefail".into()];
+    for job in &jobs {
+        ytdl_commands.extend(job.script_lines());
+    }
     std::fs::write(&ytdl_script_path, ytdl_commands.join("\n"))
         .map_err(SError::io(&ytdl_script_path))?;
     info!(
-        "wrote {} commands to {}",
+        "wrote {} commands for {} videos to {}",
         ytdl_commands.len(),
+        jobs.len(),
         ytdl_script_path.display()
     );
-
-    if ytdl_commands.len() == 2 {
-        for video_id in &all_videos {
-            synth_browse_dir(&video_id.next_id)?;
-        }
-    } else {
-        info!("skip browse synth")
-    }
-
     Ok(())
 }
 
-fn load_root_collection_id(downloader: &mut Downloader) -> SResult<String> {
-    let content = downloader.fetch(DownType::HTML, "")?;
-    extract_original_id(&content.body)
-}
+fn run_download(
+    global_args: &GlobalArgs,
+    global_config: &GlobalConfig,
+    options: &DownloadOptions,
+) -> SResult<()> {
+    let jobs = plan_jobs(global_args, global_config)?;
+    if jobs.is_empty() {
+        info!("nothing to download");
+        return Ok(());
+    }
+    if global_args.dry_run {
+        return Ok(());
+    }
+    if global_args.offline {
+        warn!("offline, not downloading {} videos", jobs.len());
+        return Ok(());
+    }
 
-fn load_collections_from_page(
-    downloader: &mut Downloader,
-    root_id: &str,
-) -> SResult<Vec<ExtractedThing>> {
-    let content = downloader.fetch(DownType::Page, root_id)?;
-    extract_collections_from_root(content.body)
+    let mut states = DownloadStates::load()?;
+    run_downloads(global_config, &mut states, jobs, options)?;
+    write_failure_report(&states, CrawlResult::load_latest().ok().as_ref())?;
+    Ok(())
 }
 
-fn load_collection(
-    downloader: &mut Downloader,
-    collection_id: &str,
-) -> SResult<Vec<ExtractedThing>> {
-    let content = downloader.fetch(DownType::Collection, collection_id)?;
-    extract_things_from_collection(content.body)
+fn download_options(args: &DownloadArgs) -> DownloadOptions {
+    DownloadOptions {
+        concurrency: args.concurrency,
+        timeout: Duration::from_mins(args.timeout_mins),
+        delay: Duration::from_secs(args.delay_secs),
+        retries: args.retries,
+        retry_backoff: Duration::from_secs(args.retry_backoff_secs),
+    }
 }
 
-fn load_youtube_dl(
+/// Fresh playback metadata of complete videos against what they looked like when downloaded or
+/// last checked. Edited ones are downloaded again into `<id>@<date>`, the original stays as is
+fn run_recheck(
+    global_args: &GlobalArgs,
     global_config: &GlobalConfig,
-    video_thing: &ExtractedThing,
-) -> SResult<Option<[String; 4]>> {
-    let video_id = &video_thing.next_id;
-    /*
-    Shockingly the backend Video ID is the public ID.
-    This site `echo qrirybcre.bar.npprqb.gi | tr 'N-ZA-Mn-za-m' 'A-Za-z'` (rot13)
-    is apparently simply a (super complex) subscription manager.
-
-    I've paid for this content already so it's fine.
-    */
-    let video_root = path([EXTRACTION_DB_ROOT, VIDEO_DL_NAME, video_id]);
-    if !video_root.exists() {
-        create_dir(&video_root).map_err(SError::io(&video_root))?;
+    min_age: TimeDelta,
+    limit: Option<usize>,
+    options: &DownloadOptions,
+) -> SResult<()> {
+    let crawl = CrawlResult::load_latest()?;
+    let mut states = DownloadStates::load()?;
+    let now = Utc::now();
+    let mut due = Vec::new();
+    let mut pending = Vec::new();
+    for video in wanted_videos(global_config, &crawl) {
+        if states.state_of(&global_config.downloader, &video.id)? != VideoState::Complete {
+            continue;
+        }
+        let meta = VideoMeta::load(&video.id)?;
+        // retried instead of re-checked, the baseline is still the old copy
+        if let Some(version) = meta.versions.iter().find(|v| v.pending) {
+            pending.push((video, version.dir_name.clone()));
+            continue;
+        }
+        let baseline = match meta.fingerprint {
+            Some(fingerprint) => Some(fingerprint),
+            None => VodFingerprint::from_video_dir(&video.id)?,
+        };
+        if let Some(baseline) = &baseline
+            && now - baseline.checked < min_age
+        {
+            trace!("recently checked {}", video.id);
+            continue;
+        }
+        due.push((video, baseline));
+    }
+    // never checked first, then oldest check
+    due.sort_by_key(|(_, baseline)| baseline.as_ref().map(|b| b.checked));
+    due.truncate(limit.unwrap_or(usize::MAX));
+    if global_args.dry_run {
+        for (video, dir_name) in &pending {
+            println!("dry-run: would retry {dir_name} of {}", video.title());
+        }
+        for (video, _) in &due {
+            println!("dry-run: would re-check {} {}", video.id, video.title());
+        }
+        return Ok(());
+    }
+    if global_args.offline {
+        warn!("offline, not re-checking {} videos", due.len());
+        return Ok(());
     }
 
-    let children = read_dir(&video_root).map_err(SError::io(&video_root))?;
-    let mut child_names: Vec<String> = children
-        .map(|v| v.map(|v| v.file_name().to_string_lossy().to_string()))
-        .try_collect()
-        .map_err(SError::io(&video_root))?;
-
-    let needs_download = if child_names.is_empty() {
-        trace!("downloading new video {video_id}");
-        true
-    } else if child_names.len() == 1 && child_names[0] == "ytdl.log" {
-        trace!("retry {video_id}");
-        true
-    } else {
-        let Some(pos) = child_names.iter().position(|v| v.ends_with(".mp4")) else {
-            panic!("missing mp4")
+    let mut downloader = Downloader::init(global_config, false, false);
+    let mut jobs = Vec::new();
+    for (video, dir_name) in &pending {
+        info!("retrying edited version {dir_name}");
+        jobs.push(download_job(
+            global_config,
+            dir_name,
+            &video.id,
+            video.title(),
+        )?);
+    }
+    let retried = jobs.len();
+    for (video, baseline) in &due {
+        let fresh = match VodFingerprint::fetch(&mut downloader, &global_config.quality, &video.id)
+        {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                warn!("re-check of {} failed: {e}", video.id);
+                continue;
+            }
         };
-        child_names.remove(pos);
+        let reasons = baseline
+            .as_ref()
+            .map(|baseline| baseline.differences(&fresh))
+            .unwrap_or_default();
+        let mut meta = VideoMeta::load(&video.id)?;
+        if reasons.is_empty() {
+            meta.fingerprint = Some(fresh);
+        } else {
+            let dir_name = format!("{}@{}", video.id, now.format("%Y-%m-%d"));
+            warn!(
+                "{} {} was edited: {}",
+                video.id,
+                video.title(),
+                reasons.join("; ")
+            );
+            let version_root = db_path([VIDEO_DL_NAME, &dir_name]);
+            if !version_root.exists() {
+                create_dir(&version_root).map_err(SError::io(&version_root))?;
+            }
+            jobs.push(download_job(
+                global_config,
+                &dir_name,
+                &video.id,
+                video.title(),
+            )?);
+            meta.versions.push(VodVersion {
+                dir_name,
+                detected: now,
+                reasons,
+                pending: true,
+                fingerprint: Some(fresh),
+            });
+        }
+        meta.save(&video.id)?;
+    }
+    info!(
+        "re-checked {} videos, {} edited",
+        due.len(),
+        jobs.len() - retried
+    );
+    if jobs.is_empty() {
+        return Ok(());
+    }
+    let versions: Vec<(String, String)> = jobs
+        .iter()
+        .map(|job| (job.bc_video_id.clone(), job.video_id.clone()))
+        .collect();
+    run_downloads(global_config, &mut states, jobs, options)?;
+    // a failed download keeps the old baseline, so the edit is not forgotten
+    for (video_id, dir_name) in versions {
+        if states.state_of(&global_config.downloader, &dir_name)? != VideoState::Complete {
+            continue;
+        }
+        let mut meta = VideoMeta::load(&video_id)?;
+        if let Some(version) = meta.versions.iter_mut().find(|v| v.dir_name == dir_name) {
+            version.pending = false;
+            meta.fingerprint = version.fingerprint.clone();
+        }
+        meta.save(&video_id)?;
+    }
+    write_failure_report(&states, Some(&crawl))?;
+    Ok(())
+}
 
-        let Some(pos) = child_names.iter().position(|v| v.ends_with(".info.json")) else {
-            panic!("missing mp4")
-        };
-        child_names.remove(pos);
+fn run_browse(global_args: &GlobalArgs, global_config: &GlobalConfig, rename: bool) -> SResult<()> {
+    let crawl = CrawlResult::load_latest()?;
+    let hierarchy = Hierarchy::build(&crawl, &global_config.episode_regexes);
+    let states = DownloadStates::load()?;
+    let mut tree = BrowseTree::new(
+        global_config.sanitizer.clone(),
+        global_config.browse_naming.clone(),
+    );
+    // everything archived, the crawl only adds collections, shows, and catalog titles
+    for video_id in list_video_ids()? {
+        if states.state_of(&global_config.downloader, &video_id)? != VideoState::Complete {
+            trace!("skip browse for undownloaded {video_id}");
+            continue;
+        }
+        tree.add_video(&global_config.downloader, &crawl, &hierarchy, &video_id)?;
+    }
+    tree.sync(global_args.dry_run, rename)
+}
 
-        let Some(pos) = child_names.iter().position(|v| v.ends_with(".jpg")) else {
-            panic!("missing mp4")
+/// With a duration tolerance, complete videos also get their media checked and broken ones are
+/// flagged for re-download
+fn run_verify(
+    global_args: &GlobalArgs,
+    global_config: &GlobalConfig,
+    probe_tolerance: Option<f64>,
+) -> SResult<()> {
+    let crawl = CrawlResult::load_latest()?;
+    let mut incomplete = 0;
+    let all_videos = wanted_videos(global_config, &crawl);
+    let mut states = DownloadStates::load()?;
+    let mut passed = 0;
+    let mut flagged = Vec::new();
+    for video_id in &all_videos {
+        let state = states.state_of(&global_config.downloader, &video_id.id)?;
+        if state != VideoState::Complete {
+            let attempts = states.get(&video_id.id).map_or(0, |r| r.attempts);
+            warn!(
+                "{:?} after {attempts} attempts {} {}",
+                state,
+                video_id.id,
+                video_id.title()
+            );
+            incomplete += 1;
+            continue;
+        }
+        let Some(tolerance) = probe_tolerance else {
+            continue;
         };
-        child_names.remove(pos);
-
-        // maybe exists
-        child_names.retain(|v| v != "ytdl.log");
-
-        if child_names.is_empty() {
-            false
+        let problems = verify_media(
+            &global_config.downloader,
+            &global_config.quality,
+            &video_id.id,
+            tolerance,
+        )?;
+        if problems.is_empty() {
+            passed += 1;
+            continue;
+        }
+        warn!("bad media {} {}", video_id.id, problems.join("; "));
+        if global_args.dry_run {
+            println!("dry-run: would flag {} for re-download", video_id.id);
         } else {
-            panic!("unknown remaining files {}", child_names.join(","))
+            states.set(&video_id.id, VideoState::Corrupt(problems.join("; ")));
         }
-    };
-
-    if needs_download {
-        let account_id = &global_config.bc_account_id;
-        let final_url = format!(
-            "https://players.brightcove.net/{account_id}/default_default/index.html?videoId={video_id}"
-        );
-        let full_root = video_root.canonicalize().unwrap();
-        Ok(Some([
-            format!("cd {}", full_root.display()),
-            format!("echo \"{}\"", video_thing.title),
-            format!(
-                "youtube-dl --write-info-json --write-thumbnail --verbose {final_url} 2>&1 | tee ytdl.log"
-            ),
-            "sleep 20".into(), // Be a nice scraper
-        ]))
-    } else {
-        Ok(None)
+        flagged.push((*video_id, problems));
     }
+    info!(
+        "verified {} videos, {incomplete} incomplete",
+        all_videos.len()
+    );
+    if probe_tolerance.is_some() {
+        info!("media of {passed} passed, {} flagged", flagged.len());
+        let report = verify_report(passed, &flagged);
+        if global_args.dry_run {
+            println!("{report}");
+        } else {
+            states.save()?;
+            write_verify_report(&report)?;
+        }
+    }
+    Ok(())
 }
 
-fn synth_browse_dir(video_id: &str) -> SResult<()> {
-    let video_root = path([EXTRACTION_DB_ROOT, VIDEO_DL_NAME, video_id]);
-    if !video_root.exists() {
-        panic!("missing video dl {video_id}")
+fn run_status(global_config: &GlobalConfig) -> SResult<()> {
+    match pending_frontier()? {
+        Some(0) | None => println!("crawl: no crawl in progress"),
+        Some(pending) => println!("crawl: in progress with {pending} queued, use crawl --resume"),
     }
 
-    let Some(info_path) = read_dir(&video_root)
-        .map_err(SError::io(&video_root))?
-        .map(|e| e.unwrap())
-        .find(|e| e.file_name().to_string_lossy().ends_with(".info.json"))
-        .map(|e| e.path())
-    else {
-        panic!("missing info.json in {}", video_root.display())
+    let snapshots = CrawlResult::list_snapshots()?;
+    let Some(latest_path) = snapshots.last() else {
+        println!("snapshots: none, run crawl first");
+        return Ok(());
     };
-    let mut info_raw = std::fs::read(&info_path).map_err(SError::io(info_path))?;
-    let info_json = simd_json::to_borrowed_value(&mut info_raw).unwrap();
-
-    let upload_date = info_json.get_str("upload_date").expect("upload_date");
-    let upload_year = &upload_date[0..4];
-    let upload_month = &upload_date[4..6];
-    let upload_day = &upload_date[6..];
-    let title = info_json.get_str("fulltitle").expect("fulltitle");
+    let crawl = CrawlResult::load_snapshot(latest_path)?;
+    let count_of = |thing_type: ThingType| {
+        crawl
+            .nodes
+            .iter()
+            .filter(|n| n.thing_type == thing_type)
+            .count()
+    };
+    println!(
+        "snapshots: {} latest {} with {} pages {} collections {} videos",
+        snapshots.len(),
+        latest_path.display(),
+        count_of(ThingType::Page),
+        count_of(ThingType::Collection),
+        count_of(ThingType::Video),
+    );
 
-    let mut final_name = format!("{upload_year}-{upload_month}-{upload_day} {title}");
-    if final_name.contains(":") {
-        trace!("removing colon from {final_name}");
-        final_name = final_name.replace(":", " -");
+    let all_videos = wanted_videos(global_config, &crawl);
+    let states = DownloadStates::load()?;
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for video_id in &all_videos {
+        let state = states.state_of(&global_config.downloader, &video_id.id)?;
+        *counts.entry(state.as_ref().to_string()).or_default() += 1;
     }
-    let final_path = path([EXTRACTION_DB_ROOT, BROWSE_NAME, &final_name]);
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(state, count)| format!("{count} {state}"))
+        .collect();
+    println!(
+        "videos: {} with {} configured missing",
+        counts.join(" "),
+        global_config.missing_videos.len()
+    );
+    Ok(())
+}
 
-    let needs_create = if final_path.is_symlink() {
-        trace!("skipping existing");
-        false
-    } else if final_path.exists() {
-        panic!("unknown existing {}", final_path.display());
-    } else {
-        true
+fn run_mark(
+    global_args: &GlobalArgs,
+    video_id: &str,
+    state: MarkState,
+    reason: Option<String>,
+) -> SResult<()> {
+    let reason = reason.unwrap_or_else(|| "marked by hand".into());
+    let mut states = DownloadStates::load()?;
+    let state = match state {
+        MarkState::Quarantined => Some(VideoState::Quarantined(reason)),
+        MarkState::Unavailable => Some(VideoState::Unavailable(reason)),
+        MarkState::Reset => None,
     };
-
-    if needs_create {
-        // we need a relative path from here
-        let target = path(["..", VIDEO_DL_NAME, video_id]);
-        info!("linking {} to {}", final_path.display(), target.display());
-        std::os::unix::fs::symlink(&target, &final_path).map_err(SError::io(final_path))?;
+    if global_args.dry_run {
+        println!("dry-run: would mark {video_id} as {state:?}");
+        return Ok(());
+    }
+    match state {
+        Some(state) => states.set(video_id, state),
+        None => states.forget(video_id),
     }
+    states.save()
+}
 
+/// Diff against the newest snapshot before this one, then save this one
+fn write_changelog(global_config: &GlobalConfig, crawl: &CrawlResult) -> SResult<()> {
+    let snapshots = CrawlResult::list_snapshots()?;
+    if let Some(previous_path) = snapshots.last() {
+        let previous = CrawlResult::load_snapshot(previous_path)?;
+        let states = DownloadStates::load()?;
+        Changelog::compare(&previous, crawl, &global_config.downloader, &states)?.write()?;
+    } else {
+        info!("first crawl snapshot, no changelog");
+    }
+    crawl.save_snapshot()?;
     Ok(())
 }
 
-fn init_logging() {
+fn init_logging(log_format: LogFormat) {
     let default_env = "trace,\
     reqwest::blocking::wait=DEBUG,\
     reqwest::blocking::client=DEBUG,\
@@ -265,8 +664,10 @@ fn init_logging() {
     let env_layer = EnvFilter::builder().parse(env_var).expect("bad env");
     let subscriber = subscriber.with(env_layer);
 
-    let filter_layer = Layer::default().compact();
-    let subscriber = subscriber.with(filter_layer);
-
-    subscriber.init()
+    match log_format {
+        LogFormat::Compact => subscriber.with(Layer::default().compact()).init(),
+        LogFormat::Pretty => subscriber.with(Layer::default().pretty()).init(),
+        LogFormat::Full => subscriber.with(Layer::default()).init(),
+        LogFormat::Json => subscriber.with(Layer::default().json()).init(),
+    }
 }
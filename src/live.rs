@@ -0,0 +1,487 @@
+use crate::brightcove::extract_playback;
+use crate::crawl::CrawlResult;
+use crate::download::{install_shutdown_handler, is_shutdown};
+use crate::downloader::{DownType, Downloader, db_path};
+use crate::err::{SError, SResult};
+use crate::extractor::{ThingType, extract_things_from_collection};
+use crate::global_config::GlobalConfig;
+use crate::hls::{
+    MANIFEST_SAVE_INTERVAL, SEGMENTS_SUFFIX, SegmentManifest, SegmentRecord,
+    resolve_media_playlist, sha256_hex,
+};
+use crate::quality::QualityPolicy;
+use crate::video_dl::{DownloadStates, VideoState};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, create_dir_all, read_dir};
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Recordings, one directory each named `<live id>@<start>`
+pub const LIVE_NAME: &str = "live";
+pub const RECORDING_NAME: &str = "recording.json";
+/// A live playlist that stops growing without EXT-X-ENDLIST has ended all the same
+const STALL_TIMEOUT: Duration = Duration::from_mins(10);
+/// Consecutive playlist or segment failures before giving up on a stream
+const MAX_FAILURES: u32 = 30;
+/// Until the playlist says otherwise
+const DEFAULT_TARGET_DURATION: f64 = 6.0;
+
+#[derive(Serialize, Deserialize)]
+pub struct LiveRecording {
+    /// Brightcove id of the live event
+    pub live_id: String,
+    pub title: String,
+    pub started: DateTime<Utc>,
+    #[serde(default)]
+    pub ended: Option<DateTime<Utc>>,
+    /// endlist, stalled, errors, or interrupted
+    #[serde(default)]
+    pub end_reason: Option<String>,
+    /// Media file in the recording directory, segments appended as they come
+    pub output: String,
+    /// Media sequence ranges the playlist dropped before we got to them, inclusive
+    #[serde(default)]
+    pub gaps: Vec<(usize, usize)>,
+    /// Catalog video the stream became, once it shows up
+    #[serde(default)]
+    pub vod_id: Option<String>,
+}
+
+/// One stream being recorded, advanced by [Self::step] from the capture loop
+struct LiveRecorder {
+    dir_name: String,
+    recording: LiveRecording,
+    manifest: SegmentManifest,
+    output: File,
+    /// None until the playback API gave a fresh url, CDN tokens expire
+    playlist_url: Option<Url>,
+    next_sequence: Option<usize>,
+    next_refresh: Instant,
+    last_new_segment: Instant,
+    last_manifest_save: Instant,
+    failures: u32,
+}
+
+impl LiveRecording {
+    pub fn load(dir_name: &str) -> SResult<Self> {
+        let recording_path = db_path([LIVE_NAME, dir_name, RECORDING_NAME]);
+        let mut raw = std::fs::read(&recording_path).map_err(SError::io(&recording_path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(&recording_path))
+    }
+
+    pub fn save(&self, dir_name: &str) -> SResult<()> {
+        let recording_path = db_path([LIVE_NAME, dir_name, RECORDING_NAME]);
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&recording_path))?;
+        std::fs::write(&recording_path, raw).map_err(SError::io(&recording_path))
+    }
+
+    /// Directory names, oldest first
+    pub fn list() -> SResult<Vec<String>> {
+        let live_root = db_path([LIVE_NAME]);
+        if !live_root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut dir_names: Vec<String> = read_dir(&live_root)
+            .map_err(SError::io(&live_root))?
+            .map(|v| v.map(|v| v.file_name().to_string_lossy().to_string()))
+            .try_collect()
+            .map_err(SError::io(&live_root))?;
+        dir_names.retain(|name| live_root.join(name).join(RECORDING_NAME).exists());
+        dir_names.sort();
+        Ok(dir_names)
+    }
+}
+
+impl LiveRecorder {
+    /// Continues an unfinished recording of the same event, e.g. after a crash
+    fn start(live_id: &str, title: &str) -> SResult<Self> {
+        let unfinished = LiveRecording::list()?.into_iter().rev().find(|dir_name| {
+            dir_name.starts_with(&format!("{live_id}@"))
+                && LiveRecording::load(dir_name).is_ok_and(|r| r.ended.is_none())
+        });
+        let (dir_name, recording, manifest) = match unfinished {
+            Some(dir_name) => {
+                info!("resuming live recording {dir_name}");
+                let recording = LiveRecording::load(&dir_name)?;
+                let manifest = SegmentManifest::load(&manifest_path(&dir_name, &recording))?;
+                (dir_name, recording, manifest)
+            }
+            None => {
+                let started = Utc::now();
+                let dir_name = format!("{live_id}@{}", started.format("%Y-%m-%dT%H-%M-%S"));
+                let recording_root = db_path([LIVE_NAME, &dir_name]);
+                create_dir_all(&recording_root).map_err(SError::io(&recording_root))?;
+                let recording = LiveRecording {
+                    live_id: live_id.to_string(),
+                    title: title.to_string(),
+                    started,
+                    ended: None,
+                    end_reason: None,
+                    // extension decided by the first playlist
+                    output: live_id.to_string(),
+                    gaps: Vec::new(),
+                    vod_id: None,
+                };
+                recording.save(&dir_name)?;
+                (dir_name, recording, SegmentManifest::default())
+            }
+        };
+        let output_path = db_path([LIVE_NAME, &dir_name, &recording.output]);
+        let output = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&output_path)
+            .map_err(SError::io(&output_path))?;
+        // a crash may have left half a segment behind the last recorded one
+        output
+            .set_len(manifest.content_length())
+            .map_err(SError::io(&output_path))?;
+        let next_sequence = manifest.segments.last().map(|s| s.index + 1);
+        Ok(Self {
+            dir_name,
+            recording,
+            manifest,
+            output,
+            playlist_url: None,
+            next_sequence,
+            next_refresh: Instant::now(),
+            last_new_segment: Instant::now(),
+            last_manifest_save: Instant::now(),
+            failures: 0,
+        })
+    }
+
+    /// Reload the playlist when due and append the new segments. Some end reason once the
+    /// stream is over. Failures only count towards [MAX_FAILURES]
+    fn step(
+        &mut self,
+        downloader: &mut Downloader,
+        quality: &QualityPolicy,
+    ) -> SResult<Option<&'static str>> {
+        if Instant::now() < self.next_refresh {
+            return Ok(None);
+        }
+        match self.fetch_new_segments(downloader, quality) {
+            Ok(true) => return Ok(Some("endlist")),
+            Ok(false) => self.failures = 0,
+            Err(e) => {
+                self.failures += 1;
+                warn!(
+                    "live {} failure {}/{MAX_FAILURES}: {e}",
+                    self.dir_name, self.failures
+                );
+                if self.failures >= MAX_FAILURES {
+                    return Ok(Some("errors"));
+                }
+                // maybe an expired CDN token
+                self.playlist_url = None;
+                self.next_refresh = Instant::now() + Duration::from_secs(self.failures as u64 * 2);
+            }
+        }
+        if self.last_new_segment.elapsed() > STALL_TIMEOUT {
+            return Ok(Some("stalled"));
+        }
+        Ok(None)
+    }
+
+    /// True once the playlist has EXT-X-ENDLIST and everything in it was appended
+    fn fetch_new_segments(
+        &mut self,
+        downloader: &mut Downloader,
+        quality: &QualityPolicy,
+    ) -> SResult<bool> {
+        let playlist_url = match &self.playlist_url {
+            Some(playlist_url) => playlist_url.clone(),
+            None => {
+                let playback =
+                    downloader.fetch_fresh(DownType::Playback, &self.recording.live_id)?;
+                let Some(hls_url) = extract_playback(playback.body)?.hls_url else {
+                    return Err(SError::hls("no HLS source in playback info"));
+                };
+                Url::parse(&hls_url)
+                    .map_err(|e| SError::hls(format!("bad master url {hls_url}: {e}")))?
+            }
+        };
+        let (media_url, playlist) = resolve_media_playlist(downloader, &playlist_url, quality)?;
+        self.playlist_url = Some(media_url);
+        let target = playlist.target_duration.unwrap_or(DEFAULT_TARGET_DURATION);
+
+        // fMP4 needs its init section in front, once
+        if self.manifest.segments.is_empty() && self.manifest.init.is_none() {
+            let ext = if playlist.init.is_some() { "mp4" } else { "ts" };
+            if let Some(init_url) = &playlist.init {
+                let body = downloader.fetch_url(init_url.as_str())?;
+                self.append(&body)?;
+                self.manifest.init = Some(SegmentRecord {
+                    index: 0,
+                    uri_path: init_url.path().to_string(),
+                    duration: 0.0,
+                    size: body.len() as u64,
+                    sha256: sha256_hex(&body),
+                });
+            }
+            self.set_output_ext(ext)?;
+        }
+
+        let first = playlist.media_sequence;
+        let next = *self.next_sequence.get_or_insert(first);
+        if next < first {
+            warn!(
+                "live {} missed segments {next} to {}",
+                self.dir_name,
+                first - 1
+            );
+            self.recording.gaps.push((next, first - 1));
+            self.recording.save(&self.dir_name)?;
+        }
+        let mut appended = 0;
+        for (offset, segment) in playlist.segments.iter().enumerate() {
+            let sequence = first + offset;
+            if sequence < next {
+                continue;
+            }
+            let body = downloader.fetch_url(segment.url.as_str())?;
+            self.append(&body)?;
+            self.manifest.segments.push(SegmentRecord {
+                index: sequence,
+                uri_path: segment.url.path().to_string(),
+                duration: segment.duration,
+                size: body.len() as u64,
+                sha256: sha256_hex(&body),
+            });
+            self.next_sequence = Some(sequence + 1);
+            appended += 1;
+        }
+        // resume truncates the output back to what the manifest covers
+        if appended > 0 && self.last_manifest_save.elapsed() >= MANIFEST_SAVE_INTERVAL {
+            self.manifest
+                .save(&manifest_path(&self.dir_name, &self.recording))?;
+            self.last_manifest_save = Instant::now();
+        }
+        if appended > 0 {
+            debug!("live {} appended {appended} segments", self.dir_name);
+            self.last_new_segment = Instant::now();
+        }
+        // half the target when nothing changed, as the HLS spec asks
+        let wait = if appended > 0 { target } else { target / 2.0 };
+        self.next_refresh = Instant::now() + Duration::from_secs_f64(wait);
+        Ok(playlist.ended)
+    }
+
+    fn append(&mut self, body: &[u8]) -> SResult<()> {
+        let output_path = db_path([LIVE_NAME, &self.dir_name, &self.recording.output]);
+        self.output
+            .write_all(body)
+            .map_err(SError::io(&output_path))
+    }
+
+    /// Rename the still empty or init only output now that the container is known
+    fn set_output_ext(&mut self, ext: &str) -> SResult<()> {
+        let old_path = db_path([LIVE_NAME, &self.dir_name, &self.recording.output]);
+        self.recording.output = format!("{}.{ext}", self.recording.live_id);
+        let new_path = db_path([LIVE_NAME, &self.dir_name, &self.recording.output]);
+        std::fs::rename(&old_path, &new_path).map_err(SError::io(&new_path))?;
+        self.manifest.output = Some(self.recording.output.clone());
+        self.recording.save(&self.dir_name)
+    }
+
+    fn finish(mut self, reason: &str) -> SResult<()> {
+        let output_path = db_path([LIVE_NAME, &self.dir_name, &self.recording.output]);
+        self.output.flush().map_err(SError::io(&output_path))?;
+        self.manifest
+            .save(&manifest_path(&self.dir_name, &self.recording))?;
+        let duration: f64 = self.manifest.segments.iter().map(|s| s.duration).sum();
+        info!(
+            "live {} ended ({reason}), {} segments, {duration:.0}s",
+            self.dir_name,
+            self.manifest.segments.len()
+        );
+        self.recording.ended = Some(Utc::now());
+        self.recording.end_reason = Some(reason.to_string());
+        self.recording.save(&self.dir_name)
+    }
+}
+
+fn manifest_path(dir_name: &str, recording: &LiveRecording) -> PathBuf {
+    db_path([
+        LIVE_NAME,
+        dir_name,
+        &format!("{}{SEGMENTS_SUFFIX}", recording.live_id),
+    ])
+}
+
+/// Videos of the live collections whose playlist has no end yet. Scheduled events without
+/// sources and finished ones are remembered in `not_live`
+fn find_live_events(
+    downloader: &mut Downloader,
+    global_config: &GlobalConfig,
+    skip: &HashSet<String>,
+    not_live: &mut HashSet<String>,
+) -> SResult<Vec<(String, String)>> {
+    let mut live = Vec::new();
+    for collection_id in &global_config.live_collections {
+        let collection = downloader.fetch_fresh(DownType::Collection, collection_id)?;
+        let listing = extract_things_from_collection(collection.body)?;
+        for thing in listing.things {
+            let id = thing.next_id;
+            if thing.next_type != ThingType::Video || skip.contains(&id) || not_live.contains(&id) {
+                continue;
+            }
+            let title = thing.meta.display_title(&id).to_string();
+            let playback = match downloader.fetch_fresh(DownType::Playback, &id) {
+                Ok(playback) => extract_playback(playback.body)?,
+                Err(e) => {
+                    // upcoming events may not be playable yet
+                    debug!("no playback for {id} yet: {e}");
+                    continue;
+                }
+            };
+            let Some(hls_url) = playback.hls_url else {
+                debug!("no HLS source for {id} yet");
+                continue;
+            };
+            let hls_url = Url::parse(&hls_url)
+                .map_err(|e| SError::hls(format!("bad master url {hls_url}: {e}")))?;
+            let (_, playlist) =
+                resolve_media_playlist(downloader, &hls_url, &global_config.quality)?;
+            if playlist.ended {
+                not_live.insert(id);
+            } else {
+                live.push((id, title));
+            }
+        }
+    }
+    Ok(live)
+}
+
+/// Poll the live collections and record every stream until it ends, until Ctrl-C.
+/// In dry-run only one poll, reporting what would be recorded
+pub fn capture_live(
+    global_config: &GlobalConfig,
+    poll: Duration,
+    offline: bool,
+    dry_run: bool,
+) -> SResult<()> {
+    assert!(
+        !global_config.live_collections.is_empty(),
+        "live capture needs live_collection= lines"
+    );
+    if offline {
+        warn!("offline, not polling for live streams");
+        return Ok(());
+    }
+    install_shutdown_handler();
+    let mut downloader = Downloader::init(global_config, false, dry_run);
+    let mut recorders: Vec<LiveRecorder> = Vec::new();
+    let mut not_live = HashSet::new();
+    let mut next_poll = Instant::now();
+    loop {
+        if is_shutdown() {
+            for recorder in recorders {
+                recorder.finish("interrupted")?;
+            }
+            return Ok(());
+        }
+        if Instant::now() >= next_poll {
+            next_poll = Instant::now() + poll;
+            // finished VODs need no watching
+            let states = DownloadStates::load()?;
+            let mut skip: HashSet<String> = recorders
+                .iter()
+                .map(|r| r.recording.live_id.clone())
+                .collect();
+            for (video_id, _) in states.iter() {
+                if states.state_of(&global_config.downloader, video_id)? == VideoState::Complete {
+                    skip.insert(video_id.clone());
+                }
+            }
+            let events = if dry_run {
+                // the playback API is fetched fresh, which dry-run forbids
+                println!(
+                    "dry-run: would poll {} live collections",
+                    global_config.live_collections.len()
+                );
+                Vec::new()
+            } else {
+                match find_live_events(&mut downloader, global_config, &skip, &mut not_live) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("live poll failed, next in {}s: {e}", poll.as_secs());
+                        Vec::new()
+                    }
+                }
+            };
+            for (live_id, title) in events {
+                info!("{live_id} {title} is live, recording");
+                recorders.push(LiveRecorder::start(&live_id, &title)?);
+            }
+        }
+        if dry_run {
+            return Ok(());
+        }
+
+        let mut finished = false;
+        let mut still_live = Vec::new();
+        for mut recorder in recorders {
+            match recorder.step(&mut downloader, &global_config.quality)? {
+                Some(reason) => {
+                    recorder.finish(reason)?;
+                    finished = true;
+                }
+                None => still_live.push(recorder),
+            }
+        }
+        recorders = still_live;
+        if finished && let Ok(crawl) = CrawlResult::load_latest() {
+            link_recordings(&crawl)?;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Tie recordings to the catalog video the stream became. Brightcove sometimes keeps the live
+/// id for the VOD, otherwise the title has to match exactly one video
+pub fn link_recordings(crawl: &CrawlResult) -> SResult<()> {
+    let normalize = |title: &str| {
+        title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    for dir_name in LiveRecording::list()? {
+        let mut recording = LiveRecording::load(&dir_name)?;
+        if recording.vod_id.is_some() {
+            continue;
+        }
+        let same_id = crawl.videos().find(|v| v.id == recording.live_id);
+        let vod = match same_id {
+            Some(vod) => Some(vod),
+            None => {
+                let title = normalize(&recording.title);
+                let mut same_title = crawl.videos().filter(|v| normalize(v.title()) == title);
+                match (same_title.next(), same_title.next()) {
+                    (Some(vod), None) => Some(vod),
+                    _ => None,
+                }
+            }
+        };
+        let Some(vod) = vod else {
+            continue;
+        };
+        info!(
+            "live recording {dir_name} became VOD {} {}",
+            vod.id,
+            vod.title()
+        );
+        recording.vod_id = Some(vod.id.clone());
+        recording.save(&dir_name)?;
+    }
+    Ok(())
+}
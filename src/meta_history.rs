@@ -0,0 +1,350 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::crawl::{CrawlResult, SNAPSHOT_TIME_FORMAT};
+use crate::downloader::{VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::video_dl::list_video_dir;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use simd_json::BorrowedValue;
+use simd_json::prelude::{ValueAsScalar, ValueObjectAccess, ValueObjectAccessAsScalar};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use strum::AsRefStr;
+use tracing::{info, warn};
+
+/// One line per captured field value, never rewritten
+pub const METADATA_HISTORY_NAME: &str = "metadata-history.jsonl";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum MetaSource {
+    Crawl,
+    InfoJson,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub video_id: String,
+    pub field: String,
+    /// None when the field went missing
+    pub value: Option<String>,
+    pub captured: DateTime<Utc>,
+    pub source: MetaSource,
+    /// Snapshot name or info.json path under vid-dl
+    pub origin: String,
+}
+
+/// A value that replaced an earlier one of the same field and source
+pub struct FieldChange<'h> {
+    pub before: &'h HistoryEntry,
+    pub after: &'h HistoryEntry,
+}
+
+/// All entries, with the timeline of each video, field, and source in capture order.
+/// Sources are kept apart, the catalog and the downloader rarely agree on a title
+#[derive(Default)]
+pub struct MetadataHistory {
+    entries: Vec<HistoryEntry>,
+    timelines: HashMap<(String, String, MetaSource), Vec<usize>>,
+}
+
+impl MetadataHistory {
+    pub fn load() -> SResult<Self> {
+        let history_path = db_path([METADATA_HISTORY_NAME]);
+        let mut history = Self::default();
+        if !history_path.exists() {
+            return Ok(history);
+        }
+        let raw = std::fs::read(&history_path).map_err(SError::io(&history_path))?;
+        let mut entries = Vec::new();
+        for line in raw.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let mut line = line.to_vec();
+            let entry: HistoryEntry =
+                simd_json::from_slice(&mut line).map_err(SError::json(&history_path))?;
+            entries.push(entry);
+        }
+        // a backfill appends captures older than the ones before it
+        entries.sort_by_key(|e| e.captured);
+        for entry in entries {
+            history.insert(entry);
+        }
+        Ok(history)
+    }
+
+    /// The capture at or just before this one, of the same field and source
+    fn previous(&self, entry: &HistoryEntry) -> Option<&HistoryEntry> {
+        let timeline = self.timelines.get(&timeline_key(entry))?;
+        let position = timeline.partition_point(|i| self.entries[*i].captured <= entry.captured);
+        position
+            .checked_sub(1)
+            .map(|previous| &self.entries[timeline[previous]])
+    }
+
+    fn insert(&mut self, entry: HistoryEntry) {
+        let index = self.entries.len();
+        let entries = &self.entries;
+        let timeline = self.timelines.entry(timeline_key(&entry)).or_default();
+        let position = timeline.partition_point(|i| entries[*i].captured <= entry.captured);
+        timeline.insert(position, index);
+        self.entries.push(entry);
+    }
+
+    /// Append the entries that change a value compared to the capture before them, wherever
+    /// they fall in time, so a backfill fills in older snapshots and importing the same one
+    /// twice adds nothing. Gives the number appended
+    pub fn record(&mut self, entries: Vec<HistoryEntry>, dry_run: bool) -> SResult<usize> {
+        let mut fresh = Vec::new();
+        for entry in entries {
+            if self
+                .previous(&entry)
+                .is_some_and(|previous| previous.value == entry.value)
+            {
+                continue;
+            }
+            self.insert(entry.clone());
+            fresh.push(entry);
+        }
+        if fresh.is_empty() || dry_run {
+            return Ok(fresh.len());
+        }
+        let history_path = db_path([METADATA_HISTORY_NAME]);
+        let mut history_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history_path)
+            .map_err(SError::io(&history_path))?;
+        for entry in &fresh {
+            let mut line = simd_json::to_vec(entry).map_err(SError::json(&history_path))?;
+            line.push(b'\n');
+            history_file
+                .write_all(&line)
+                .map_err(SError::io(&history_path))?;
+        }
+        Ok(fresh.len())
+    }
+
+    /// Every entry of the video, oldest first
+    pub fn of_video(&self, video_id: &str) -> Vec<&HistoryEntry> {
+        let mut entries: Vec<&HistoryEntry> = self
+            .entries
+            .iter()
+            .filter(|e| e.video_id == video_id)
+            .collect();
+        entries.sort_by_key(|e| e.captured);
+        entries
+    }
+
+    /// Changes captured in `[since, until)`, the first capture of a field is not a change. A
+    /// backfill can leave the same value twice in a row, that is not one either
+    pub fn changes_between(
+        &self,
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+    ) -> Vec<FieldChange<'_>> {
+        let mut ordered: Vec<&HistoryEntry> = self.entries.iter().collect();
+        ordered.sort_by_key(|e| e.captured);
+        let mut previous: HashMap<(String, String, MetaSource), &HistoryEntry> = HashMap::new();
+        let mut changes = Vec::new();
+        for entry in ordered {
+            let before = previous.insert(timeline_key(entry), entry);
+            let day = entry.captured.date_naive();
+            let in_window =
+                since.is_none_or(|since| day >= since) && until.is_none_or(|until| day < until);
+            if let Some(before) = before
+                && before.value != entry.value
+                && in_window
+            {
+                changes.push(FieldChange {
+                    before,
+                    after: entry,
+                });
+            }
+        }
+        changes
+    }
+}
+
+fn timeline_key(entry: &HistoryEntry) -> (String, String, MetaSource) {
+    (entry.video_id.clone(), entry.field.clone(), entry.source)
+}
+
+/// Title, description, artwork, season, episode, and the collections each video is in
+pub fn crawl_entries(crawl: &CrawlResult) -> Vec<HistoryEntry> {
+    let origin = crawl.finished.format(SNAPSHOT_TIME_FORMAT).to_string();
+    let mut entries = Vec::new();
+    for video in crawl.videos() {
+        let collections: BTreeSet<&str> = crawl
+            .graph
+            .collections_containing(&video.id)
+            .into_iter()
+            .map(|e| e.from.id.as_str())
+            .collect();
+        let collections = collections.into_iter().collect::<Vec<_>>().join(",");
+        let fields = [
+            ("title", video.meta.title.clone()),
+            ("description", video.meta.description.clone()),
+            ("artwork", video.meta.artwork.clone()),
+            ("season", video.meta.season.map(|v| v.to_string())),
+            ("episode", video.meta.episode.map(|v| v.to_string())),
+            ("collections", Some(collections)),
+        ];
+        for (field, value) in fields {
+            entries.push(HistoryEntry {
+                video_id: video.id.clone(),
+                field: field.into(),
+                value,
+                captured: crawl.finished,
+                source: MetaSource::Crawl,
+                origin: origin.clone(),
+            });
+        }
+    }
+    entries
+}
+
+/// From the info.json in a vid-dl directory, captured when it was written. Dated versions go
+/// under the video id inside
+pub fn info_json_entries(
+    backend: &DownloaderBackend,
+    dir_name: &str,
+) -> SResult<Vec<HistoryEntry>> {
+    let child_names = list_video_dir(dir_name)?;
+    let Some(info_name) = backend
+        .artifacts()
+        .find(&child_names, ArtifactKind::InfoJson)
+    else {
+        return Ok(Vec::new());
+    };
+    let info_path = db_path([VIDEO_DL_NAME, dir_name, info_name]);
+    let captured: DateTime<Utc> = std::fs::metadata(&info_path)
+        .and_then(|m| m.modified())
+        .map_err(SError::io(&info_path))?
+        .into();
+    let mut raw = std::fs::read(&info_path).map_err(SError::io(&info_path))?;
+    // one truncated or hand-edited file must not stop a backfill of every directory
+    let json: BorrowedValue = match simd_json::to_borrowed_value(&mut raw) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("skipping {}: {e}", info_path.display());
+            return Ok(Vec::new());
+        }
+    };
+    let video_id = json.get_str("id").unwrap_or(dir_name).to_string();
+    let string = |key: &str| json.get_str(key).map(String::from);
+    let fields = [
+        ("title", string("title")),
+        ("description", string("description")),
+        ("thumbnail", string("thumbnail")),
+        ("upload_date", string("upload_date")),
+        (
+            "duration",
+            json.get("duration")
+                .and_then(|d| d.cast_f64())
+                .map(|d| format!("{d:.1}")),
+        ),
+    ];
+    Ok(fields
+        .into_iter()
+        .map(|(field, value)| HistoryEntry {
+            video_id: video_id.clone(),
+            field: field.into(),
+            value,
+            captured,
+            source: MetaSource::InfoJson,
+            origin: format!("{dir_name}/{info_name}"),
+        })
+        .collect())
+}
+
+/// Field by field, each value with when and where it was seen
+pub fn video_history_markdown(video_id: &str, entries: &[&HistoryEntry]) -> String {
+    let mut md = format!("# Metadata history of {video_id}\n");
+    let fields: BTreeSet<(&str, &str)> = entries
+        .iter()
+        .map(|e| (e.field.as_str(), e.source.as_ref()))
+        .collect();
+    for (field, source) in fields {
+        writeln!(md, "\n## {field} ({source})\n").unwrap();
+        for entry in entries
+            .iter()
+            .filter(|e| e.field == field && e.source.as_ref() == source)
+        {
+            writeln!(
+                md,
+                "- {} {} from {}",
+                entry.captured.format("%Y-%m-%d %H:%M"),
+                md_value(&entry.value),
+                entry.origin
+            )
+            .unwrap();
+        }
+    }
+    md
+}
+
+pub fn changes_markdown(
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    changes: &[FieldChange],
+) -> String {
+    let window = |date: Option<NaiveDate>| date.map_or("...".to_string(), |d| d.to_string());
+    let videos: BTreeSet<&str> = changes.iter().map(|c| c.after.video_id.as_str()).collect();
+    let mut md = format!(
+        "# Metadata changes {} to {}\n\n{} changes to {} videos\n\n",
+        window(since),
+        window(until),
+        changes.len(),
+        videos.len()
+    );
+    for change in changes {
+        writeln!(
+            md,
+            "- {} `{}` {} ({}): {} -> {}",
+            change.after.captured.format("%Y-%m-%d %H:%M"),
+            change.after.video_id,
+            change.after.field,
+            change.after.source.as_ref(),
+            md_value(&change.before.value),
+            md_value(&change.after.value)
+        )
+        .unwrap();
+    }
+    md
+}
+
+fn md_value(value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\n', " ")),
+        None => "(none)".into(),
+    }
+}
+
+/// Every crawl snapshot and every info.json, for archives older than the history
+pub fn backfill(
+    backend: &DownloaderBackend,
+    history: &mut MetadataHistory,
+    dry_run: bool,
+) -> SResult<usize> {
+    let mut entries = Vec::new();
+    for snapshot_path in CrawlResult::list_snapshots()? {
+        entries.extend(crawl_entries(&CrawlResult::load_snapshot(&snapshot_path)?));
+    }
+    let vid_root = db_path([VIDEO_DL_NAME]);
+    for dir in std::fs::read_dir(&vid_root).map_err(SError::io(&vid_root))? {
+        let dir = dir.map_err(SError::io(&vid_root))?;
+        if dir.path().is_dir() {
+            entries.extend(info_json_entries(
+                backend,
+                &dir.file_name().to_string_lossy(),
+            )?);
+        }
+    }
+    // oldest first, so every capture gets compared to the one before it
+    entries.sort_by_key(|e| e.captured);
+    let recorded = history.record(entries, dry_run)?;
+    info!("backfilled {recorded} metadata history entries");
+    Ok(recorded)
+}
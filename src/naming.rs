@@ -0,0 +1,243 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+/// The names browse has always used
+pub const DEFAULT_BROWSE_TEMPLATE: &str = "{date} {title}";
+
+static TEMPLATE_FIELD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([a-z_]+)\}").unwrap());
+
+const TEMPLATE_FIELDS: [&str; 13] = [
+    "date",
+    "time",
+    "year",
+    "month",
+    "day",
+    "title",
+    "catalog_title",
+    "show",
+    "season",
+    "episode",
+    "episode_code",
+    "id",
+    "duration",
+];
+
+/// When a video aired. youtube-dl's upload_date is only a day, which no timezone can shift
+pub enum Aired {
+    At(DateTime<Utc>),
+    Day(NaiveDate),
+}
+
+/// What a template can put in a name. Season and episode come from the hierarchy
+pub struct NameFields<'a> {
+    pub aired: Aired,
+    /// From the info.json, as browse used before templates
+    pub title: &'a str,
+    pub catalog_title: &'a str,
+    pub id: &'a str,
+    pub show: Option<&'a str>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    /// Seconds
+    pub duration: Option<f64>,
+}
+
+/// BROWSE_TEMPLATE= with {date} {time} {year} {month} {day} {title} {catalog_title} {show}
+/// {season} {episode} {episode_code} {id} {duration} fields, dates in BROWSE_TIMEZONE=
+#[derive(Clone)]
+pub struct NamingTemplate {
+    template: String,
+    timezone: Tz,
+}
+
+impl Default for NamingTemplate {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_BROWSE_TEMPLATE.into(),
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+impl NamingTemplate {
+    pub fn from_config(template: Option<&str>, timezone: Option<&str>) -> Self {
+        let template = template.unwrap_or(DEFAULT_BROWSE_TEMPLATE).to_string();
+        for field in TEMPLATE_FIELD.captures_iter(&template) {
+            let field = &field[1];
+            assert!(
+                TEMPLATE_FIELDS.contains(&field),
+                "unknown BROWSE_TEMPLATE field {{{field}}}"
+            );
+        }
+        assert!(
+            TEMPLATE_FIELD.is_match(&template),
+            "BROWSE_TEMPLATE without fields"
+        );
+        let timezone = match timezone {
+            Some(timezone) => timezone
+                .parse()
+                .unwrap_or_else(|e| panic!("bad BROWSE_TIMEZONE {timezone}: {e}")),
+            None => Tz::UTC,
+        };
+        Self { template, timezone }
+    }
+
+    /// Template and timezone, a change to either renames links
+    pub fn scheme(&self) -> String {
+        format!("{} in {}", self.template, self.timezone)
+    }
+
+    /// The day in the configured timezone, also picks the browse-months directory
+    pub fn local_date(&self, aired: &Aired) -> NaiveDate {
+        match aired {
+            Aired::At(at) => at.with_timezone(&self.timezone).date_naive(),
+            Aired::Day(day) => *day,
+        }
+    }
+
+    /// Unsanitized. Brackets and whitespace around fields that came out empty are collapsed
+    pub fn render(&self, fields: &NameFields) -> String {
+        let date = self.local_date(&fields.aired);
+        let mut any_empty = false;
+        let name = TEMPLATE_FIELD.replace_all(&self.template, |field: &Captures| {
+            let value = match &field[1] {
+                "date" => date.format("%Y-%m-%d").to_string(),
+                "time" => match fields.aired {
+                    Aired::At(at) => at.with_timezone(&self.timezone).format("%H-%M").to_string(),
+                    Aired::Day(_) => String::new(),
+                },
+                "year" => date.format("%Y").to_string(),
+                "month" => date.format("%m").to_string(),
+                "day" => date.format("%d").to_string(),
+                "title" => fields.title.to_string(),
+                "catalog_title" => fields.catalog_title.to_string(),
+                "show" => fields.show.unwrap_or_default().to_string(),
+                "season" => fields.season.map(|s| format!("{s:02}")).unwrap_or_default(),
+                "episode" => fields
+                    .episode
+                    .map(|e| format!("{e:02}"))
+                    .unwrap_or_default(),
+                "episode_code" => match (fields.season, fields.episode) {
+                    (Some(season), Some(episode)) => format!("S{season:02}E{episode:02}"),
+                    (None, Some(episode)) => format!("E{episode:02}"),
+                    _ => String::new(),
+                },
+                "id" => fields.id.to_string(),
+                "duration" => fields.duration.map(format_duration).unwrap_or_default(),
+                field => unreachable!("checked field {field}"),
+            };
+            any_empty |= value.is_empty();
+            value
+        });
+        if any_empty {
+            let name = name.replace("()", "").replace("[]", "");
+            name.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            name.into_owned()
+        }
+    }
+}
+
+/// Like 45s, 42m, or 1h02m
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match (seconds / 3600, seconds % 3600 / 60) {
+        (0, 0) => format!("{seconds}s"),
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h{minutes:02}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(aired: Aired) -> NameFields<'static> {
+        NameFields {
+            aired,
+            title: "Evening News",
+            catalog_title: "News at Six",
+            id: "6300000000001",
+            show: None,
+            season: None,
+            episode: None,
+            duration: None,
+        }
+    }
+
+    fn at(rfc3339: &str) -> Aired {
+        Aired::At(DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc())
+    }
+
+    #[test]
+    fn default_template() {
+        let naming = NamingTemplate::default();
+        assert_eq!(
+            naming.render(&fields(at("2024-01-05T02:00:00Z"))),
+            "2024-01-05 Evening News"
+        );
+    }
+
+    #[test]
+    fn empty_fields_collapse() {
+        let naming = NamingTemplate::from_config(
+            Some("{date} {show} {episode_code} [{duration}] ({time}) {title}"),
+            None,
+        );
+        let day = Aired::Day(NaiveDate::from_ymd_opt(2024, 1, 5).unwrap());
+        assert_eq!(naming.render(&fields(day)), "2024-01-05 Evening News");
+
+        let mut full = fields(at("2024-01-05T02:00:00Z"));
+        full.show = Some("Daily");
+        full.season = Some(3);
+        full.episode = Some(7);
+        full.duration = Some(1805.0);
+        assert_eq!(
+            naming.render(&full),
+            "2024-01-05 Daily S03E07 [30m] (02-00) Evening News"
+        );
+    }
+
+    #[test]
+    fn timezone_rolls_the_day_back() {
+        let naming = NamingTemplate::from_config(
+            Some("{year}/{month}/{day} {time} {title}"),
+            Some("America/New_York"),
+        );
+        let aired = at("2024-01-05T02:00:00Z");
+        assert_eq!(
+            naming.local_date(&aired),
+            NaiveDate::from_ymd_opt(2024, 1, 4).unwrap()
+        );
+        assert_eq!(
+            naming.render(&fields(aired)),
+            "2024/01/04 21-00 Evening News"
+        );
+        // a day alone has no time to shift
+        let day = Aired::Day(NaiveDate::from_ymd_opt(2024, 1, 5).unwrap());
+        assert_eq!(naming.render(&fields(day)), "2024/01/05 Evening News");
+    }
+
+    #[test]
+    fn episode_code_without_season() {
+        let naming = NamingTemplate::from_config(Some("{episode_code} {catalog_title}"), None);
+        let mut fields = fields(at("2024-01-05T02:00:00Z"));
+        fields.episode = Some(12);
+        assert_eq!(naming.render(&fields), "E12 News at Six");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(44.6), "45s");
+        assert_eq!(format_duration(2520.0), "42m");
+        assert_eq!(format_duration(3720.0), "1h02m");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown BROWSE_TEMPLATE field {name}")]
+    fn unknown_field() {
+        NamingTemplate::from_config(Some("{name}"), None);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::brightcove::PLAYBACK_NAME;
+use crate::crawl::CrawlNode;
+use crate::downloader::{VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::hls::{SEGMENTS_SUFFIX, SegmentManifest};
+use crate::quality::{QualityMode, QualityPolicy};
+use crate::video_dl::list_video_dir;
+use simd_json::BorrowedValue;
+use simd_json::prelude::{
+    ValueAsArray, ValueAsScalar, ValueObjectAccess, ValueObjectAccessAsScalar,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+pub const VERIFY_REPORT_NAME: &str = "verify-report.md";
+
+/// What ffprobe says about one media file
+pub struct Probe {
+    pub duration: Option<f64>,
+    pub has_video: bool,
+    pub has_audio: bool,
+}
+
+/// Err with ffprobe's complaint when it can't read the container
+pub fn ffprobe(media_path: &Path) -> SResult<Result<Probe, String>> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_format",
+            "-show_streams",
+            "-of",
+            "json",
+        ])
+        .arg(media_path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(SError::io(media_path))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let complaint = stderr.lines().next().unwrap_or("ffprobe failed").trim();
+        return Ok(Err(complaint.to_string()));
+    }
+    let mut raw = output.stdout;
+    let json: BorrowedValue = simd_json::to_borrowed_value(&mut raw).unwrap();
+    let streams = json.get("streams").and_then(|v| v.as_array());
+    // a poster embedded as a video stream doesn't count
+    let has_stream = |codec_type: &str| {
+        streams.is_some_and(|streams| {
+            streams.iter().any(|s| {
+                s.get_str("codec_type") == Some(codec_type)
+                    && s.get("disposition")
+                        .and_then(|d| d.get_u64("attached_pic"))
+                        .unwrap_or(0)
+                        == 0
+            })
+        })
+    };
+    Ok(Ok(Probe {
+        // ffprobe prints numbers as strings
+        duration: json
+            .get("format")
+            .and_then(|f| f.get_str("duration"))
+            .and_then(|d| d.parse().ok()),
+        has_video: has_stream("video"),
+        has_audio: has_stream("audio"),
+    }))
+}
+
+/// Problems with the media of a downloaded video, empty when it looks fine
+pub fn verify_media(
+    backend: &DownloaderBackend,
+    quality: &QualityPolicy,
+    video_id: &str,
+    duration_tolerance: f64,
+) -> SResult<Vec<String>> {
+    let spec = backend.artifacts();
+    let video_root = db_path([VIDEO_DL_NAME, video_id]);
+    let child_names = list_video_dir(video_id)?;
+    let media_names: Vec<&String> = child_names
+        .iter()
+        .filter(|name| spec.classify(name) == Some(ArtifactKind::Media))
+        .collect();
+    let mut problems = Vec::new();
+    if media_names.is_empty() {
+        problems.push("no media file".to_string());
+        return Ok(problems);
+    }
+
+    let expected_sizes = expected_sizes(&video_root, &child_names)?;
+    let expected_duration = expected_duration(&video_root, &child_names)?;
+    let (mut has_video, mut has_audio) = (false, false);
+    let mut longest: Option<f64> = None;
+    for name in media_names {
+        let media_path = video_root.join(name);
+        let probe = match ffprobe(&media_path)? {
+            Ok(probe) => probe,
+            Err(complaint) => {
+                problems.push(format!("{name} unreadable: {complaint}"));
+                continue;
+            }
+        };
+        debug!(
+            "{name} duration {:?} video {} audio {}",
+            probe.duration, probe.has_video, probe.has_audio
+        );
+        has_video |= probe.has_video;
+        has_audio |= probe.has_audio;
+        if let Some(duration) = probe.duration {
+            longest = Some(longest.map_or(duration, |l| l.max(duration)));
+        }
+
+        if let Some(expected) = expected_sizes.get(name.as_str()) {
+            let size = std::fs::metadata(&media_path)
+                .map_err(SError::io(&media_path))?
+                .len();
+            if size != *expected {
+                problems.push(format!("{name} is {size} bytes, expected {expected}"));
+            }
+        }
+    }
+
+    // unreadable or truncated, the stream checks would only repeat it
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+    if !has_audio {
+        problems.push("no audio stream".into());
+    }
+    if !has_video && quality.mode != QualityMode::AudioOnly {
+        problems.push("no video stream".into());
+    }
+    if let Some(expected) = expected_duration {
+        // long videos get some slack for rounding in the catalog
+        let tolerance = duration_tolerance.max(expected * 0.01);
+        match longest {
+            Some(actual) if (actual - expected).abs() > tolerance => {
+                problems.push(format!("duration {actual:.1}s, expected {expected:.1}s"))
+            }
+            None => problems.push("no duration".into()),
+            _ => {}
+        }
+    }
+    Ok(problems)
+}
+
+/// From the info.json, else the Brightcove playback response
+pub fn expected_duration(video_root: &Path, child_names: &[String]) -> SResult<Option<f64>> {
+    if let Some(info_name) = child_names.iter().find(|n| n.ends_with(".info.json")) {
+        let info_path = video_root.join(info_name);
+        let mut raw = std::fs::read(&info_path).map_err(SError::io(&info_path))?;
+        let json: BorrowedValue = simd_json::to_borrowed_value(&mut raw).unwrap();
+        if let Some(duration) = json.get("duration").and_then(|d| d.cast_f64()) {
+            return Ok(Some(duration));
+        }
+    }
+    if child_names.iter().any(|n| n == PLAYBACK_NAME) {
+        let playback_path = video_root.join(PLAYBACK_NAME);
+        let mut raw = std::fs::read(&playback_path).map_err(SError::io(&playback_path))?;
+        let json: BorrowedValue = simd_json::to_borrowed_value(&mut raw).unwrap();
+        // milliseconds
+        return Ok(json.get_u64("duration").map(|ms| ms as f64 / 1000.0));
+    }
+    Ok(None)
+}
+
+/// Media file name to byte count, from the native segment checksums or the single format
+/// youtube-dl reported a size for
+fn expected_sizes(video_root: &Path, child_names: &[String]) -> SResult<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    for name in child_names.iter().filter(|n| n.ends_with(SEGMENTS_SUFFIX)) {
+        let manifest = SegmentManifest::load(&video_root.join(name))?;
+        if let Some(output) = &manifest.output {
+            sizes.insert(output.clone(), manifest.content_length());
+        }
+    }
+    if let Some(info_name) = child_names.iter().find(|n| n.ends_with(".info.json")) {
+        let info_path = video_root.join(info_name);
+        let mut raw = std::fs::read(&info_path).map_err(SError::io(&info_path))?;
+        let json: BorrowedValue = simd_json::to_borrowed_value(&mut raw).unwrap();
+        // merged formats have no single size
+        if json.get("requested_formats").is_none()
+            && let Some(size) = json.get_u64("filesize")
+            && let Some(filename) = json.get_str("_filename")
+            && let Some(name) = Path::new(filename).file_name()
+        {
+            sizes.insert(name.to_string_lossy().to_string(), size);
+        }
+    }
+    Ok(sizes)
+}
+
+/// Flagged videos with their problems, titles from the crawl
+pub fn verify_report(passed: usize, flagged: &[(&CrawlNode, Vec<String>)]) -> String {
+    let mut md = "# Media verification\n\n".to_string();
+    writeln!(
+        md,
+        "{passed} passed, {} flagged for re-download\n",
+        flagged.len()
+    )
+    .unwrap();
+    for (video, problems) in flagged {
+        writeln!(
+            md,
+            "- `{}` {}: {}",
+            video.id,
+            video.title(),
+            problems.join("; ")
+        )
+        .unwrap();
+    }
+    md
+}
+
+pub fn write_verify_report(report: &str) -> SResult<()> {
+    let report_path = db_path([VERIFY_REPORT_NAME]);
+    std::fs::write(&report_path, report).map_err(SError::io(&report_path))?;
+    info!("wrote verification report to {}", report_path.display());
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use crate::video_meta::Rendition;
+use std::fmt::Write;
+
+/// Codec prefixes of an HLS CODECS attribute that mean there is a picture
+const VIDEO_CODEC_PREFIXES: &[&str] = &["avc1", "avc3", "hvc1", "hev1", "vp8", "vp09", "av01"];
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum QualityMode {
+    /// Best within the caps, preferred codec and container first
+    #[default]
+    Best,
+    Lowest,
+    /// Every video rendition, one file each
+    KeepAll,
+    /// Just the sound, for talk content
+    AudioOnly,
+}
+
+/// Which rendition of a video to download, the same for every backend
+#[derive(Clone, Default, Debug)]
+pub struct QualityPolicy {
+    pub mode: QualityMode,
+    pub max_height: Option<u32>,
+    pub max_kbps: Option<u64>,
+    /// Codec prefix like avc1 or hvc1
+    pub codec: Option<String>,
+    /// Only youtube-dl and yt-dlp can remux, the native downloader keeps what the stream has
+    pub container: Option<String>,
+}
+
+impl QualityPolicy {
+    /// QUALITY= best (default), lowest, keep-all, or audio-only, capped by QUALITY_MAX_HEIGHT=
+    /// and QUALITY_MAX_KBPS=, preferring QUALITY_CODEC= and QUALITY_CONTAINER=
+    pub fn from_config(
+        mode: Option<&str>,
+        max_height: Option<&str>,
+        max_kbps: Option<&str>,
+        codec: Option<&str>,
+        container: Option<&str>,
+    ) -> Self {
+        let mode = match mode.unwrap_or("best") {
+            "best" => QualityMode::Best,
+            "lowest" => QualityMode::Lowest,
+            "keep-all" => QualityMode::KeepAll,
+            "audio-only" => QualityMode::AudioOnly,
+            unknown => panic!("unknown QUALITY {unknown}"),
+        };
+        Self {
+            mode,
+            max_height: max_height.map(|v| v.parse().expect("QUALITY_MAX_HEIGHT")),
+            max_kbps: max_kbps.map(|v| v.parse().expect("QUALITY_MAX_KBPS")),
+            codec: codec.map(String::from),
+            container: container.map(|v| v.to_ascii_lowercase()),
+        }
+    }
+
+    /// Recorded with the chosen rendition, e.g. best max-height:720 codec:avc1
+    pub fn describe(&self) -> String {
+        let mut description = match self.mode {
+            QualityMode::Best => "best",
+            QualityMode::Lowest => "lowest",
+            QualityMode::KeepAll => "keep-all",
+            QualityMode::AudioOnly => "audio-only",
+        }
+        .to_string();
+        if let Some(height) = self.max_height {
+            write!(description, " max-height:{height}").unwrap();
+        }
+        if let Some(kbps) = self.max_kbps {
+            write!(description, " max-kbps:{kbps}").unwrap();
+        }
+        if let Some(codec) = &self.codec {
+            write!(description, " codec:{codec}").unwrap();
+        }
+        if let Some(container) = &self.container {
+            write!(description, " container:{container}").unwrap();
+        }
+        description
+    }
+
+    /// Indexes of the renditions to download. Keep-all gives them lowest first.
+    /// Falls back to the lowest video when nothing is within the caps or there is no audio-only one
+    pub fn select(&self, candidates: &[Rendition]) -> Vec<usize> {
+        let video: Vec<usize> = (0..candidates.len())
+            .filter(|i| !candidates[*i].audio_only)
+            .collect();
+        let size = |i: &usize| (candidates[*i].height, candidates[*i].bitrate);
+        let lowest = video.iter().copied().min_by_key(size);
+        let chosen = match self.mode {
+            QualityMode::Best => video
+                .iter()
+                .copied()
+                .filter(|i| self.within_caps(&candidates[*i]))
+                .max_by_key(|i| (self.preference(&candidates[*i]), size(i)))
+                .or(lowest),
+            QualityMode::Lowest => lowest,
+            QualityMode::KeepAll => {
+                let mut all = video;
+                all.sort_by_key(size);
+                return all;
+            }
+            QualityMode::AudioOnly => (0..candidates.len())
+                .filter(|i| candidates[*i].audio_only)
+                .max_by_key(|i| candidates[*i].bitrate)
+                .or(lowest),
+        };
+        chosen.into_iter().collect()
+    }
+
+    fn within_caps(&self, rendition: &Rendition) -> bool {
+        let height_ok = match (self.max_height, rendition.height) {
+            (Some(max), Some(height)) => height <= max,
+            _ => true,
+        };
+        let kbps_ok = match (self.max_kbps, rendition.bitrate) {
+            (Some(max), Some(bitrate)) => bitrate / 1000 <= max,
+            _ => true,
+        };
+        height_ok && kbps_ok
+    }
+
+    /// Codec first, then container
+    fn preference(&self, rendition: &Rendition) -> (bool, bool) {
+        let codec = self.codec.as_ref().is_some_and(|wanted| {
+            rendition
+                .codecs
+                .as_deref()
+                .is_some_and(|codecs| codecs.split(',').any(|c| c.trim().starts_with(wanted)))
+        });
+        let container =
+            self.container.is_some() && self.container.as_deref() == rendition.container.as_deref();
+        (codec, container)
+    }
+
+    /// youtube-dl format selector, None leaves it at its own best
+    pub fn ytdl_format(&self) -> Option<String> {
+        let mut caps = String::new();
+        if let Some(height) = self.max_height {
+            write!(caps, "[height<=?{height}]").unwrap();
+        }
+        if let Some(kbps) = self.max_kbps {
+            write!(caps, "[tbr<=?{kbps}]").unwrap();
+        }
+        let mut preferred = String::new();
+        if let Some(codec) = &self.codec {
+            write!(preferred, "[vcodec^={codec}]").unwrap();
+        }
+        if let Some(container) = &self.container {
+            write!(preferred, "[ext={container}]").unwrap();
+        }
+        match self.mode {
+            QualityMode::AudioOnly => Some("bestaudio/worst".into()),
+            QualityMode::Lowest => Some("worst".into()),
+            QualityMode::KeepAll => None,
+            QualityMode::Best if caps.is_empty() && preferred.is_empty() => None,
+            QualityMode::Best => {
+                let mut choices = Vec::new();
+                if !preferred.is_empty() {
+                    choices.push(format!("bestvideo{caps}{preferred}+bestaudio"));
+                    choices.push(format!("best{caps}{preferred}"));
+                }
+                choices.push(format!("bestvideo{caps}+bestaudio"));
+                choices.push(format!("best{caps}"));
+                // like the native downloader, nothing within the caps means the smallest
+                choices.push(if caps.is_empty() { "best" } else { "worst" }.into());
+                Some(choices.join("/"))
+            }
+        }
+    }
+
+    /// Extra youtube-dl and yt-dlp arguments
+    pub fn ytdl_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(format) = self.ytdl_format() {
+            args.extend(["-f".into(), format]);
+        }
+        if self.mode == QualityMode::KeepAll {
+            // one file per format instead of each overwriting the last
+            args.extend([
+                "--all-formats".into(),
+                "-o".into(),
+                "%(title)s-%(id)s.f%(format_id)s.%(ext)s".into(),
+            ]);
+        }
+        if let Some(container) = &self.container
+            && self.mode != QualityMode::AudioOnly
+        {
+            args.extend(["--merge-output-format".into(), container.clone()]);
+        }
+        args
+    }
+}
+
+/// Whether an HLS CODECS attribute lists a video codec
+pub fn has_video_codec(codecs: &str) -> bool {
+    codecs
+        .split(',')
+        .any(|c| VIDEO_CODEC_PREFIXES.iter().any(|p| c.trim().starts_with(p)))
+}
@@ -0,0 +1,179 @@
+use crate::brightcove::{PLAYBACK_NAME, extract_playback};
+use crate::downloader::{DownType, Downloader, VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::hls::{SEGMENTS_SUFFIX, SegmentManifest, resolve_media_playlist, sha256_hex};
+use crate::probe::expected_duration;
+use crate::quality::QualityPolicy;
+use crate::video_dl::list_video_dir;
+use crate::video_meta::Rendition;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Allowed duration difference, the API rounds to milliseconds but re-encodes shift frames
+const DURATION_SLACK_MS: u64 = 1000;
+
+/// What a published video looked like at some point, compared to spot edits after the stream
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VodFingerprint {
+    pub checked: DateTime<Utc>,
+    pub duration_ms: Option<u64>,
+    /// Sorted, Brightcove re-encodes every rendition on an edit
+    pub mp4_sizes: Vec<u64>,
+    /// Milliseconds, of the HLS rendition the quality policy picks
+    pub segment_durations: Vec<u64>,
+    /// Segment index to sha256, of the first, middle, and last segment
+    pub segment_samples: BTreeMap<usize, String>,
+}
+
+/// A re-download next to the original after an edit
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VodVersion {
+    /// Directory under vid-dl, `<id>@<date>`
+    pub dir_name: String,
+    pub detected: DateTime<Utc>,
+    pub reasons: Vec<String>,
+    /// Not downloaded yet, re-planned by every re-check until it is
+    #[serde(default)]
+    pub pending: bool,
+    /// Of the edited video, becomes the baseline once the download is complete
+    #[serde(default)]
+    pub fingerprint: Option<VodFingerprint>,
+}
+
+impl VodFingerprint {
+    /// Fresh playback info, the media playlist, and a few segments
+    pub fn fetch(
+        downloader: &mut Downloader,
+        quality: &QualityPolicy,
+        video_id: &str,
+    ) -> SResult<Self> {
+        let playback = downloader.fetch_fresh(DownType::Playback, video_id)?;
+        let playback = extract_playback(playback.body)?;
+        let mut fingerprint = Self {
+            checked: Utc::now(),
+            duration_ms: playback.duration_ms,
+            mp4_sizes: mp4_sizes(&playback.mp4_sources),
+            segment_durations: Vec::new(),
+            segment_samples: BTreeMap::new(),
+        };
+        let Some(hls_url) = &playback.hls_url else {
+            return Ok(fingerprint);
+        };
+        let master_url = Url::parse(hls_url)
+            .map_err(|e| SError::hls(format!("bad master url {hls_url}: {e}")))?;
+        let (_, media) = resolve_media_playlist(downloader, &master_url, quality)?;
+
+        fingerprint.segment_durations = media
+            .segments
+            .iter()
+            .map(|s| (s.duration * 1000.0).round() as u64)
+            .collect();
+        for index in sample_indexes(media.segments.len()) {
+            let body = downloader.fetch_url(media.segments[index].url.as_str())?;
+            fingerprint.segment_samples.insert(index, sha256_hex(&body));
+        }
+        Ok(fingerprint)
+    }
+
+    /// From what the download left behind, so the first re-check already has something to
+    /// compare against. Only the duration for youtube-dl, None when even that is missing
+    pub fn from_video_dir(video_id: &str) -> SResult<Option<Self>> {
+        let video_root = db_path([VIDEO_DL_NAME, video_id]);
+        let child_names = list_video_dir(video_id)?;
+        let duration_ms = expected_duration(&video_root, &child_names)?
+            .map(|secs| (secs * 1000.0).round() as u64);
+        let Some(duration_ms) = duration_ms else {
+            return Ok(None);
+        };
+        let modified = std::fs::metadata(&video_root)
+            .and_then(|m| m.modified())
+            .map_err(SError::io(&video_root))?;
+
+        let playback_path = video_root.join(PLAYBACK_NAME);
+        let mp4_sizes = if playback_path.exists() {
+            let raw = std::fs::read(&playback_path).map_err(SError::io(&playback_path))?;
+            mp4_sizes(&extract_playback(raw)?.mp4_sources)
+        } else {
+            Vec::new()
+        };
+        // keep-all downloads have one manifest per rendition, those start from the first re-check
+        let manifest_path = video_root.join(format!("video{SEGMENTS_SUFFIX}"));
+        let segments = if manifest_path.exists() {
+            SegmentManifest::load(&manifest_path)?.segments
+        } else {
+            Vec::new()
+        };
+        Ok(Some(Self {
+            checked: modified.into(),
+            duration_ms: Some(duration_ms),
+            mp4_sizes,
+            segment_durations: segments
+                .iter()
+                .map(|s| (s.duration * 1000.0).round() as u64)
+                .collect(),
+            segment_samples: sample_indexes(segments.len())
+                .into_iter()
+                .map(|index| (index, segments[index].sha256.clone()))
+                .collect(),
+        }))
+    }
+
+    /// Human readable differences, empty when it looks the same. Whatever either side
+    /// doesn't know is skipped
+    pub fn differences(&self, fresh: &Self) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if let (Some(old), Some(new)) = (self.duration_ms, fresh.duration_ms)
+            && old.abs_diff(new) > DURATION_SLACK_MS
+        {
+            reasons.push(format!(
+                "duration {:.1}s to {:.1}s",
+                old as f64 / 1000.0,
+                new as f64 / 1000.0
+            ));
+        }
+        if !self.mp4_sizes.is_empty()
+            && !fresh.mp4_sizes.is_empty()
+            && self.mp4_sizes != fresh.mp4_sizes
+        {
+            reasons.push("MP4 rendition sizes changed".into());
+        }
+        let both_segmented =
+            !self.segment_durations.is_empty() && !fresh.segment_durations.is_empty();
+        if both_segmented && self.segment_durations.len() != fresh.segment_durations.len() {
+            reasons.push(format!(
+                "{} segments to {}",
+                self.segment_durations.len(),
+                fresh.segment_durations.len()
+            ));
+        } else if both_segmented && self.segment_durations != fresh.segment_durations {
+            reasons.push("segment durations changed".into());
+        } else if both_segmented {
+            for (index, sha256) in &self.segment_samples {
+                if fresh
+                    .segment_samples
+                    .get(index)
+                    .is_some_and(|s| s != sha256)
+                {
+                    reasons.push(format!("segment {index} content changed"));
+                }
+            }
+        }
+        reasons
+    }
+}
+
+fn mp4_sizes(sources: &[Rendition]) -> Vec<u64> {
+    let mut sizes: Vec<u64> = sources.iter().filter_map(|s| s.size).collect();
+    sizes.sort();
+    sizes
+}
+
+/// First, middle, and last, edits tend to be at the ends
+fn sample_indexes(count: usize) -> Vec<usize> {
+    let mut indexes = vec![0, count / 2, count.saturating_sub(1)];
+    indexes.dedup();
+    indexes.retain(|i| *i < count);
+    indexes
+}
@@ -0,0 +1,196 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Which filesystems the browse names have to survive being copied to
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum SanitizeProfile {
+    /// Only `/` and control characters are a problem
+    #[default]
+    Posix,
+    /// SMB shares and NTFS: the exFAT characters, reserved device names, case-insensitive
+    Windows,
+    /// SD cards and USB drives: no `"*/:<>?\|`, no trailing dots or spaces, case-insensitive
+    Exfat,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sanitizer {
+    pub profile: SanitizeProfile,
+    /// Per path component. UTF-8 bytes, which also keeps Windows under its UTF-16 limit
+    pub max_bytes: usize,
+}
+
+/// CON.txt is as bad as CON
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self {
+            profile: SanitizeProfile::default(),
+            max_bytes: 255,
+        }
+    }
+}
+
+impl Sanitizer {
+    /// FILENAME_PROFILE= posix (default), windows, or exfat. FILENAME_MAX_BYTES= defaults to 255
+    pub fn from_config(profile: Option<&str>, max_bytes: Option<&str>) -> Self {
+        let profile = match profile.unwrap_or("posix") {
+            "posix" => SanitizeProfile::Posix,
+            "windows" => SanitizeProfile::Windows,
+            "exfat" => SanitizeProfile::Exfat,
+            unknown => panic!("unknown FILENAME_PROFILE {unknown}"),
+        };
+        let max_bytes = max_bytes.map_or(255, |v| v.parse().expect("FILENAME_MAX_BYTES"));
+        // room for a collision suffix
+        assert!(max_bytes >= 32, "FILENAME_MAX_BYTES below 32");
+        Self { profile, max_bytes }
+    }
+
+    /// One path component, NFC normalized
+    pub fn name(&self, name: &str) -> String {
+        self.name_with_suffix(name, "")
+    }
+
+    /// Like [Self::name], the suffix survives truncation
+    pub fn name_with_suffix(&self, name: &str, suffix: &str) -> String {
+        let portable = self.profile != SanitizeProfile::Posix;
+        let mut clean = String::new();
+        for c in name.nfc() {
+            match c {
+                // as before, so existing posix links keep their names
+                ':' => clean.push_str(" -"),
+                '/' => clean.push('-'),
+                c if c.is_control() => clean.push(' '),
+                '\\' | '|' if portable => clean.push('-'),
+                '"' if portable => clean.push('\''),
+                '<' if portable => clean.push('('),
+                '>' if portable => clean.push(')'),
+                '?' | '*' if portable => {}
+                c => clean.push(c),
+            }
+        }
+
+        // room for the _ in front of a reserved Windows name
+        let prefix = usize::from(self.profile == SanitizeProfile::Windows);
+        let budget = self.max_bytes - suffix.len() - prefix;
+        if clean.len() > budget {
+            let mut end = budget;
+            while !clean.is_char_boundary(end) {
+                end -= 1;
+            }
+            clean.truncate(end);
+        }
+        if portable {
+            // Windows drops them silently, two names could become one
+            clean.truncate(clean.trim_end_matches(['.', ' ']).len());
+        }
+        clean.push_str(suffix);
+
+        if self.profile == SanitizeProfile::Windows {
+            let stem = clean.split('.').next().unwrap_or_default().trim_end();
+            if WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+            {
+                clean.insert(0, '_');
+            }
+        }
+        if clean.trim().is_empty() || clean == "." || clean == ".." {
+            return "_".into();
+        }
+        clean
+    }
+
+    /// Names the filesystem would treat as the same file
+    pub fn collision_key(&self, name: &str) -> String {
+        match self.profile {
+            SanitizeProfile::Posix => name.to_string(),
+            SanitizeProfile::Windows | SanitizeProfile::Exfat => name.to_lowercase(),
+        }
+    }
+}
+
+/// Last 8 characters, Brightcove ids of one account share their leading digits
+pub fn short_id(video_id: &str) -> &str {
+    let start = video_id
+        .char_indices()
+        .rev()
+        .nth(7)
+        .map_or(0, |(start, _)| start);
+    &video_id[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(profile: SanitizeProfile, max_bytes: usize) -> Sanitizer {
+        Sanitizer { profile, max_bytes }
+    }
+
+    #[test]
+    fn windows_reserved_names() {
+        let windows = sanitizer(SanitizeProfile::Windows, 255);
+        assert_eq!(windows.name("CON"), "_CON");
+        assert_eq!(windows.name("con.txt"), "_con.txt");
+        assert_eq!(windows.name("LPT1 .mp4"), "_LPT1 .mp4");
+        assert_eq!(windows.name("CONSOLE"), "CONSOLE");
+        // only Windows reserves them
+        assert_eq!(sanitizer(SanitizeProfile::Exfat, 255).name("CON"), "CON");
+    }
+
+    #[test]
+    fn portable_characters() {
+        let exfat = sanitizer(SanitizeProfile::Exfat, 255);
+        assert_eq!(exfat.name("Who? <Me>: \"A|B\"..."), "Who (Me) - 'A-B'");
+        assert_eq!(exfat.name("..."), "_");
+        let posix = Sanitizer::default();
+        assert_eq!(posix.name("Who? A/B: C."), "Who? A-B - C.");
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        let posix = sanitizer(SanitizeProfile::Posix, 10);
+        // three bytes each, 10 falls inside the fourth
+        assert_eq!(posix.name("日本語日本語"), "日本語");
+        assert_eq!(posix.name("abcdefghijkl"), "abcdefghij");
+    }
+
+    #[test]
+    fn suffix_survives_truncation() {
+        let posix = sanitizer(SanitizeProfile::Posix, 10);
+        assert_eq!(posix.name_with_suffix("abcdefghijkl", " [x]"), "abcdef [x]");
+        assert_eq!(posix.name_with_suffix("日本語日本語", " [x]"), "日本 [x]");
+    }
+
+    #[test]
+    fn reserved_prefix_stays_within_max_bytes() {
+        let windows = sanitizer(SanitizeProfile::Windows, 12);
+        let name = windows.name("CON.abcdefghijkl");
+        assert_eq!(name, "_CON.abcdefg");
+        assert_eq!(name.len(), 12);
+        let name = windows.name_with_suffix("aux.abcdefghijkl", " [x]");
+        assert_eq!(name, "_aux.abc [x]");
+        assert_eq!(windows.name("abcdefghijklmn").len(), 11);
+    }
+
+    #[test]
+    fn collision_keys() {
+        assert_ne!(
+            Sanitizer::default().collision_key("News"),
+            Sanitizer::default().collision_key("NEWS")
+        );
+        let exfat = sanitizer(SanitizeProfile::Exfat, 255);
+        assert_eq!(exfat.collision_key("News"), exfat.collision_key("NEWS"));
+    }
+
+    #[test]
+    fn short_ids() {
+        assert_eq!(short_id("6301234567890"), "34567890");
+        assert_eq!(short_id("12345678"), "12345678");
+        assert_eq!(short_id("abc"), "abc");
+    }
+}
@@ -0,0 +1,321 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::downloader::{Downloader, VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::video_dl::list_video_dir;
+use crate::video_meta::VideoMeta;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use simd_json::BorrowedValue;
+use simd_json::prelude::{ValueAsArray, ValueObjectAccess, ValueObjectAccessAsScalar};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::LazyLock;
+use strum::AsRefStr;
+use tracing::{info, warn};
+
+/// Which text tracks to keep
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum SubtitleMode {
+    None,
+    /// As served, usually WebVTT
+    #[default]
+    All,
+    /// As served plus an SRT copy of each caption and subtitle track
+    Srt,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum TextTrackKind {
+    /// Includes sound descriptions, for the hard of hearing
+    Captions,
+    Subtitles,
+    Chapters,
+}
+
+/// A caption, subtitle, or chapter file in the video directory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextTrack {
+    pub file: String,
+    /// BCP 47, None when the source didn't say
+    pub language: Option<String>,
+    pub kind: TextTrackKind,
+    pub label: Option<String>,
+    /// Converted copy next to it
+    pub srt_file: Option<String>,
+}
+
+/// A text track listed by the Brightcove playback API
+pub struct PlaybackTextTrack {
+    pub src: String,
+    pub language: Option<String>,
+    pub kind: TextTrackKind,
+    pub label: Option<String>,
+}
+
+/// en, pt-BR, zh-Hans. What youtube-dl puts between the title and the extension
+static LANGUAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z]{2,3}([-_][A-Za-z0-9]{2,8})*$").unwrap());
+/// Cue settings, voice and class spans, and karaoke timestamps SRT has no use for
+static VTT_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"</?(c|v|lang|ruby|rt)(\.[^ >]*)?( [^>]*)?>|<\d[\d:.]*>").unwrap()
+});
+
+impl SubtitleMode {
+    /// SUBTITLES= all (default), srt, or none
+    pub fn from_config(value: Option<&str>) -> Self {
+        match value.unwrap_or("all") {
+            "all" => Self::All,
+            "srt" => Self::Srt,
+            "none" => Self::None,
+            unknown => panic!("unknown SUBTITLES {unknown}"),
+        }
+    }
+
+    /// Extra youtube-dl arguments, yt-dlp spells them differently
+    pub fn ytdl_args(&self, yt_dlp: bool) -> Vec<String> {
+        let args: &[&str] = match (self, yt_dlp) {
+            (Self::None, _) => &[],
+            (_, false) => &["--all-subs"],
+            (_, true) => &["--write-subs", "--sub-langs", "all"],
+        };
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+}
+
+impl TextTrackKind {
+    /// Brightcove also has descriptions and metadata tracks, which we skip
+    fn from_brightcove(kind: &str) -> Option<Self> {
+        match kind {
+            "captions" => Some(Self::Captions),
+            "subtitles" => Some(Self::Subtitles),
+            "chapters" => Some(Self::Chapters),
+            _ => None,
+        }
+    }
+}
+
+/// The text_tracks of a playback API response, https sources preferred
+pub fn extract_text_tracks(json: &BorrowedValue) -> Vec<PlaybackTextTrack> {
+    let Some(tracks) = json.get("text_tracks").and_then(|v| v.as_array()) else {
+        return Vec::new();
+    };
+    tracks
+        .iter()
+        .filter_map(|track| {
+            let kind = TextTrackKind::from_brightcove(track.get_str("kind")?)?;
+            let mut srcs: Vec<&str> = track
+                .get("sources")
+                .and_then(|v| v.as_array())
+                .map(|sources| sources.iter().filter_map(|s| s.get_str("src")).collect())
+                .unwrap_or_default();
+            srcs.extend(track.get_str("src"));
+            srcs.sort_by_key(|src| !src.starts_with("https://"));
+            Some(PlaybackTextTrack {
+                src: srcs.first()?.to_string(),
+                language: track.get_str("srclang").map(String::from),
+                kind,
+                label: track.get_str("label").map(String::from),
+            })
+        })
+        .collect()
+}
+
+/// Save each track as `<id>.<lang>.vtt`, the name media servers pick up next to `<id>.mp4`.
+/// Captions get a `.cc` and chapters a `.chapters` before the extension. A track that fails is
+/// left out and added to `missing_assets`
+pub fn download_text_tracks(
+    downloader: &mut Downloader,
+    video_root: &Path,
+    video_id: &str,
+    tracks: &[PlaybackTextTrack],
+    missing_assets: &mut Vec<String>,
+) -> Vec<TextTrack> {
+    let mut used_names = HashSet::new();
+    let mut saved = Vec::new();
+    for track in tracks {
+        let language = track.language.as_deref().unwrap_or("und");
+        let flag = match track.kind {
+            TextTrackKind::Captions => ".cc",
+            TextTrackKind::Subtitles => "",
+            TextTrackKind::Chapters => ".chapters",
+        };
+        let mut file = format!("{video_id}.{language}{flag}.vtt");
+        // two tracks of the same language and kind with different labels
+        let mut n = 1;
+        while !used_names.insert(file.clone()) {
+            n += 1;
+            file = format!("{video_id}.{language}{flag}.{n}.vtt");
+        }
+        let track_path = video_root.join(&file);
+        let saved_track = downloader
+            .fetch_url(&track.src)
+            .and_then(|body| std::fs::write(&track_path, body).map_err(SError::io(&track_path)));
+        if let Err(e) = saved_track {
+            warn!("{video_id} text track {file} not saved: {e}");
+            missing_assets.push(format!("{file}: {e}"));
+            continue;
+        }
+        saved.push(TextTrack {
+            file,
+            language: track.language.clone(),
+            kind: track.kind,
+            label: track.label.clone(),
+            srt_file: None,
+        });
+    }
+    saved
+}
+
+/// What youtube-dl and yt-dlp wrote, by file name. Our own SRT copies are skipped
+pub fn find_text_tracks(backend: &DownloaderBackend, video_id: &str) -> SResult<Vec<TextTrack>> {
+    let spec = backend.artifacts();
+    let child_names = list_video_dir(video_id)?;
+    let subtitle_names: Vec<&String> = child_names
+        .iter()
+        .filter(|name| spec.classify(name) == Some(ArtifactKind::Subtitle))
+        .collect();
+    let mut tracks = Vec::new();
+    for name in &subtitle_names {
+        let Some((stem, ext)) = name.rsplit_once('.') else {
+            continue;
+        };
+        if ext == "srt"
+            && subtitle_names
+                .iter()
+                .any(|other| **other == format!("{stem}.vtt"))
+        {
+            continue;
+        }
+        let (stem, captions) = match stem.strip_suffix(".cc") {
+            Some(stem) => (stem, true),
+            None => (stem, false),
+        };
+        let language = stem
+            .rsplit_once('.')
+            .map(|(_, language)| language)
+            .filter(|language| LANGUAGE.is_match(language));
+        tracks.push(TextTrack {
+            file: name.to_string(),
+            language: language.map(String::from),
+            kind: if captions {
+                TextTrackKind::Captions
+            } else {
+                TextTrackKind::Subtitles
+            },
+            label: None,
+            srt_file: None,
+        });
+    }
+    tracks.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(tracks)
+}
+
+/// Convert to SRT if asked, then list the tracks in the video's [VideoMeta]
+pub fn record_text_tracks(
+    video_id: &str,
+    mode: SubtitleMode,
+    mut tracks: Vec<TextTrack>,
+) -> SResult<()> {
+    if mode == SubtitleMode::Srt {
+        for track in &mut tracks {
+            if track.kind == TextTrackKind::Chapters {
+                continue;
+            }
+            let Some(stem) = track.file.strip_suffix(".vtt") else {
+                continue;
+            };
+            let srt_file = format!("{stem}.srt");
+            let vtt_path = db_path([VIDEO_DL_NAME, video_id, &track.file]);
+            let srt_path = db_path([VIDEO_DL_NAME, video_id, &srt_file]);
+            let vtt = std::fs::read(&vtt_path).map_err(SError::io(&vtt_path))?;
+            std::fs::write(&srt_path, vtt_to_srt(&String::from_utf8_lossy(&vtt)))
+                .map_err(SError::io(&srt_path))?;
+            track.srt_file = Some(srt_file);
+        }
+    }
+    if !tracks.is_empty() {
+        let files: Vec<&str> = tracks.iter().map(|t| t.file.as_str()).collect();
+        info!("{video_id} text tracks {}", files.join(","));
+    }
+    let mut meta = VideoMeta::load(video_id)?;
+    meta.text_tracks = tracks;
+    meta.save(video_id)
+}
+
+/// Cues renumbered, hours always present, comma before the millis, settings and spans dropped
+pub fn vtt_to_srt(vtt: &str) -> String {
+    let vtt = vtt.replace("\r\n", "\n");
+    let mut srt = String::new();
+    let mut number = 0;
+    for block in vtt.split("\n\n") {
+        let mut lines = block.lines().skip_while(|line| !line.contains("-->"));
+        let Some(timing) = lines.next() else {
+            // header, NOTE, STYLE, and REGION blocks
+            continue;
+        };
+        let mut times = timing.split("-->").map(|t| t.split_whitespace().next());
+        let (Some(Some(start)), Some(Some(end))) = (times.next(), times.next()) else {
+            warn!("bad WebVTT cue timing {timing}");
+            continue;
+        };
+        number += 1;
+        writeln!(
+            srt,
+            "{number}\n{} --> {}",
+            srt_timestamp(start),
+            srt_timestamp(end)
+        )
+        .unwrap();
+        for line in lines {
+            writeln!(srt, "{}", VTT_TAG.replace_all(line, "")).unwrap();
+        }
+        srt.push('\n');
+    }
+    srt
+}
+
+/// 01:02.500 to 00:01:02,500, SRT wants two digit hours where WebVTT allows one
+fn srt_timestamp(vtt: &str) -> String {
+    let with_hours = match vtt.matches(':').count() {
+        1 => format!("00:{vtt}"),
+        _ => match vtt.split_once(':') {
+            Some((hours, rest)) if hours.len() < 2 => format!("{hours:0>2}:{rest}"),
+            _ => vtt.to_string(),
+        },
+    };
+    with_hours.replace('.', ",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_timings() {
+        assert_eq!(srt_timestamp("01:02.500"), "00:01:02,500");
+        assert_eq!(srt_timestamp("1:01:02.500"), "01:01:02,500");
+        assert_eq!(srt_timestamp("10:01:02.000"), "10:01:02,000");
+    }
+
+    #[test]
+    fn converts_and_renumbers_cues() {
+        let vtt = "WEBVTT\n\nNOTE written by hand\n\nintro\n00:01.500 --> 00:02.000 align:start position:10%\n\
+            <v Bob>Hello</v> <c.yellow>there</c>\nsecond <00:01.750>line\n\n\
+            00:03.000 -->\n\n\
+            01:00:00.000 --> 01:00:01.250\nBye\n";
+        assert_eq!(
+            vtt_to_srt(vtt),
+            "1\n00:00:01,500 --> 00:00:02,000\nHello there\nsecond line\n\n\
+            2\n01:00:00,000 --> 01:00:01,250\nBye\n\n"
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let vtt = "WEBVTT\r\n\r\n00:00.000 --> 00:01.000\r\nHi\r\n";
+        assert_eq!(vtt_to_srt(vtt), "1\n00:00:00,000 --> 00:00:01,000\nHi\n\n");
+    }
+}
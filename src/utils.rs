@@ -3,8 +3,7 @@ pub fn last_position_of(input: &str, needle: u8) -> usize {
         .as_bytes()
         .iter()
         .enumerate()
-        .filter(|(_i, c)| **c == needle)
-        .next_back()
+        .rfind(|(_i, c)| **c == needle)
         .unwrap()
         .0
 }
@@ -12,9 +11,39 @@ pub fn last_position_of(input: &str, needle: u8) -> usize {
 pub fn get_only<T>(input: impl IntoIterator<Item = T>, error: &str) -> T {
     let mut iter = input.into_iter();
     let only = iter.next().expect(error);
-    if let Some(_) = iter.next() {
+    if iter.next().is_some() {
         panic!("{error} - too big")
     } else {
         only
     }
 }
+
+/// Single quote for bash, nothing inside is interpreted
+pub fn shell_quote(input: &str) -> String {
+    format!("'{}'", input.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn quotes() {
+        assert_eq!(shell_quote("plain"), "'plain'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME `id` $(id)"), "'$HOME `id` $(id)'");
+    }
+
+    #[test]
+    fn shell_reads_back_the_input() {
+        let input = "it's $HOME, \"`id`\" \\n $(id) ''\n!";
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf '%s' {}", shell_quote(input)))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), input);
+    }
+}
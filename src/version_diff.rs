@@ -0,0 +1,473 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::downloader::{Downloader, VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::hls::{SEGMENTS_SUFFIX, SegmentManifest, resolve_media_playlist, sha256_hex};
+use crate::probe::ffprobe;
+use crate::quality::QualityPolicy;
+use crate::video_dl::list_video_dir;
+use reqwest::Url;
+use serde::Serialize;
+use similar::{Algorithm, DiffOp, capture_diff_slices};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use strum::AsRefStr;
+use tracing::{debug, info, warn};
+
+/// Diffs as JSON and edit decision lists, by the two versions compared
+pub const VERSION_DIFFS_NAME: &str = "version-diffs";
+
+/// A vid-dl directory, or a playlist on the CDN
+pub enum VersionSource {
+    Archived(String),
+    Playlist(Url),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum EditKind {
+    Inserted,
+    Removed,
+    Changed,
+}
+
+/// How precise the range boundaries are
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Resolution {
+    Segments,
+    /// Decoded video and audio frame hashes from ffmpeg. Only bit-identical frames compare equal,
+    /// a re-encode of the same content shows as one changed range
+    Frames,
+}
+
+/// Seconds in either version. An insertion has an empty old range at the point it goes in
+#[derive(Serialize)]
+pub struct EditRange {
+    pub kind: EditKind,
+    pub old_start: f64,
+    pub old_end: f64,
+    pub new_start: f64,
+    pub new_end: f64,
+    pub resolution: Resolution,
+}
+
+#[derive(Serialize)]
+pub struct VersionDiff {
+    pub old: String,
+    pub new: String,
+    pub old_duration: f64,
+    pub new_duration: f64,
+    pub edits: Vec<EditRange>,
+}
+
+/// A segment, or a frame once refined
+struct Unit {
+    start: f64,
+    duration: f64,
+    hash: String,
+}
+
+/// One side ready to compare
+struct Version {
+    label: String,
+    units: Vec<Unit>,
+    /// For the frame fallback, archived versions only
+    media: Option<PathBuf>,
+}
+
+impl VersionSource {
+    /// `http(s)://` is a playlist, anything else a directory under vid-dl
+    pub fn parse(arg: &str) -> SResult<Self> {
+        if arg.starts_with("https://") || arg.starts_with("http://") {
+            let url = Url::parse(arg).map_err(|e| SError::hls(format!("bad url {arg}: {e}")))?;
+            Ok(Self::Playlist(url))
+        } else {
+            Ok(Self::Archived(arg.to_string()))
+        }
+    }
+
+    /// For file names, playlists go by a hash of their url
+    fn label(&self) -> String {
+        match self {
+            Self::Archived(dir_name) => dir_name.clone(),
+            Self::Playlist(url) => {
+                format!("playlist-{}", &sha256_hex(url.as_str().as_bytes())[..12])
+            }
+        }
+    }
+
+    /// Segment hashes from the native downloader's checksums or by fetching every segment
+    fn load(
+        &self,
+        backend: &DownloaderBackend,
+        downloader: &mut Downloader,
+        quality: &QualityPolicy,
+    ) -> SResult<Version> {
+        let label = self.label();
+        match self {
+            Self::Archived(dir_name) => {
+                let video_root = db_path([VIDEO_DL_NAME, dir_name]);
+                let child_names = list_video_dir(dir_name)?;
+                let media = backend
+                    .artifacts()
+                    .find(&child_names, ArtifactKind::Media)
+                    .map(|name| video_root.join(name));
+                let manifest =
+                    SegmentManifest::load(&video_root.join(format!("video{SEGMENTS_SUFFIX}")))?;
+                let mut start = 0.0;
+                let units = manifest
+                    .segments
+                    .into_iter()
+                    .map(|segment| {
+                        let unit = Unit {
+                            start,
+                            duration: segment.duration,
+                            hash: segment.sha256,
+                        };
+                        start += segment.duration;
+                        unit
+                    })
+                    .collect();
+                Ok(Version {
+                    label,
+                    units,
+                    media,
+                })
+            }
+            Self::Playlist(url) => {
+                let (_, media) = resolve_media_playlist(downloader, url, quality)?;
+                info!("hashing {} segments of {url}", media.segments.len());
+                let mut start = 0.0;
+                let mut units = Vec::new();
+                for segment in &media.segments {
+                    let body = downloader.fetch_url(segment.url.as_str())?;
+                    units.push(Unit {
+                        start,
+                        duration: segment.duration,
+                        hash: sha256_hex(&body),
+                    });
+                    start += segment.duration;
+                }
+                Ok(Version {
+                    label,
+                    units,
+                    media: None,
+                })
+            }
+        }
+    }
+}
+
+impl Version {
+    fn duration(&self) -> f64 {
+        self.units.last().map_or(0.0, |u| u.start + u.duration)
+    }
+
+    /// Start of the unit, or the end of the version past the last one
+    fn time_at(&self, index: usize) -> f64 {
+        self.units.get(index).map_or(self.duration(), |u| u.start)
+    }
+}
+
+/// Segment hashes first. Changed segment ranges, and versions without segment checksums like
+/// youtube-dl downloads, are compared by decoded frames where the media is on disk
+pub fn diff_versions(
+    backend: &DownloaderBackend,
+    downloader: &mut Downloader,
+    quality: &QualityPolicy,
+    old: &VersionSource,
+    new: &VersionSource,
+) -> SResult<VersionDiff> {
+    let mut old = old.load(backend, downloader, quality)?;
+    let mut new = new.load(backend, downloader, quality)?;
+    let mut resolution = Resolution::Segments;
+    if old.units.is_empty() || new.units.is_empty() {
+        let (Some(old_media), Some(new_media)) = (&old.media, &new.media) else {
+            return Err(SError::hls(
+                "no segment checksums and no media to compare frames of",
+            ));
+        };
+        info!("no segment checksums, comparing all frames");
+        old.units = frame_units(old_media, 0.0, None)?;
+        new.units = frame_units(new_media, 0.0, None)?;
+        resolution = Resolution::Frames;
+    }
+
+    let mut edits = Vec::new();
+    for edit in diff_units(&old, &new, resolution) {
+        let refine = edit.kind == EditKind::Changed && resolution == Resolution::Segments;
+        let (Some(old_media), Some(new_media), true) = (&old.media, &new.media, refine) else {
+            edits.push(edit);
+            continue;
+        };
+        match refine_by_frames(old_media, new_media, &edit) {
+            Ok(refined) => edits.extend(refined),
+            Err(complaint) => {
+                warn!("kept segment boundaries: {complaint}");
+                edits.push(edit);
+            }
+        }
+    }
+    Ok(VersionDiff {
+        old: old.label.clone(),
+        new: new.label.clone(),
+        old_duration: old.duration(),
+        new_duration: new.duration(),
+        edits,
+    })
+}
+
+/// Myers over the unit hashes, runs of equal units dropped
+fn diff_units(old: &Version, new: &Version, resolution: Resolution) -> Vec<EditRange> {
+    let old_hashes: Vec<&str> = old.units.iter().map(|u| u.hash.as_str()).collect();
+    let new_hashes: Vec<&str> = new.units.iter().map(|u| u.hash.as_str()).collect();
+    capture_diff_slices(Algorithm::Myers, &old_hashes, &new_hashes)
+        .into_iter()
+        .filter_map(|op| {
+            let (kind, old_range, new_range) = match op {
+                DiffOp::Equal { .. } => return None,
+                DiffOp::Delete {
+                    old_index,
+                    old_len,
+                    new_index,
+                } => (
+                    EditKind::Removed,
+                    old_index..old_index + old_len,
+                    new_index..new_index,
+                ),
+                DiffOp::Insert {
+                    old_index,
+                    new_index,
+                    new_len,
+                } => (
+                    EditKind::Inserted,
+                    old_index..old_index,
+                    new_index..new_index + new_len,
+                ),
+                DiffOp::Replace {
+                    old_index,
+                    old_len,
+                    new_index,
+                    new_len,
+                } => (
+                    EditKind::Changed,
+                    old_index..old_index + old_len,
+                    new_index..new_index + new_len,
+                ),
+            };
+            Some(EditRange {
+                kind,
+                old_start: old.time_at(old_range.start),
+                old_end: old.time_at(old_range.end),
+                new_start: new.time_at(new_range.start),
+                new_end: new.time_at(new_range.end),
+                resolution,
+            })
+        })
+        .collect()
+}
+
+/// Re-packaged segments of the same encode drop out, real edits get frame accurate bounds
+fn refine_by_frames(
+    old_media: &Path,
+    new_media: &Path,
+    edit: &EditRange,
+) -> SResult<Vec<EditRange>> {
+    let old = Version {
+        label: String::new(),
+        units: frame_units(
+            old_media,
+            edit.old_start,
+            Some(edit.old_end - edit.old_start),
+        )?,
+        media: None,
+    };
+    let new = Version {
+        label: String::new(),
+        units: frame_units(
+            new_media,
+            edit.new_start,
+            Some(edit.new_end - edit.new_start),
+        )?,
+        media: None,
+    };
+    if old.units.is_empty() || new.units.is_empty() {
+        return Err(SError::hls("ffmpeg decoded no frames"));
+    }
+    let mut refined = diff_units(&old, &new, Resolution::Frames);
+    // the last frame's end is only as good as its duration, stay within the segments
+    for range in &mut refined {
+        range.old_end = range.old_end.min(edit.old_end);
+        range.new_end = range.new_end.min(edit.new_end);
+    }
+    debug!(
+        "{:.3}s to {:.3}s refined to {} ranges",
+        edit.old_start,
+        edit.old_end,
+        refined.len()
+    );
+    Ok(refined)
+}
+
+/// Decoded frame hashes of the first video stream, each with the hashes of the audio frames
+/// that start during it, so a muted or redubbed section differs too. Audio only media gets one
+/// unit per audio frame. Times are absolute, ffmpeg's restart at the seek point
+fn frame_units(media_path: &Path, start: f64, duration: Option<f64>) -> SResult<Vec<Unit>> {
+    let (has_video, has_audio) = match ffprobe(media_path)? {
+        Ok(probe) if probe.has_video || probe.has_audio => (probe.has_video, probe.has_audio),
+        Ok(_) => {
+            return Err(SError::hls(format!(
+                "no streams in {}",
+                media_path.display()
+            )));
+        }
+        Err(complaint) => return Err(SError::hls(complaint)),
+    };
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error", "-ss", &format!("{start:.3}")]);
+    command.arg("-i").arg(media_path);
+    if let Some(duration) = duration {
+        command.args(["-t", &format!("{duration:.3}")]);
+    }
+    // output stream 0 is the video when there is one
+    if has_video {
+        command.args(["-map", "0:v:0"]);
+    }
+    if has_audio {
+        command.args(["-map", "0:a:0"]);
+    }
+    let output = command
+        .args(["-f", "framemd5", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(SError::io(media_path))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let complaint = stderr.lines().next().unwrap_or("ffmpeg failed").trim();
+        return Err(SError::hls(format!(
+            "{}: {complaint}",
+            media_path.display()
+        )));
+    }
+
+    let mut streams = parse_framemd5(&String::from_utf8_lossy(&output.stdout), start);
+    let mut units = streams.remove(&0).unwrap_or_default();
+    if has_video
+        && let Some(audio) = streams.remove(&1)
+        && !units.is_empty()
+    {
+        for frame in audio {
+            // before the first video frame goes with the first
+            let index = units
+                .partition_point(|unit| unit.start <= frame.start)
+                .saturating_sub(1);
+            let unit = &mut units[index];
+            unit.hash.push('+');
+            unit.hash.push_str(&frame.hash);
+        }
+    }
+    Ok(units)
+}
+
+/// Frames by output stream. `#tb 0: 1/90000` headers, then stream, dts, pts, duration, size, hash
+fn parse_framemd5(framemd5: &str, start: f64) -> BTreeMap<u32, Vec<Unit>> {
+    let mut time_bases: BTreeMap<u32, f64> = BTreeMap::new();
+    let mut streams: BTreeMap<u32, Vec<Unit>> = BTreeMap::new();
+    for line in framemd5.lines() {
+        if let Some(tb) = line.strip_prefix("#tb ") {
+            if let Some((stream, tb)) = tb.split_once(':')
+                && let Ok(stream) = stream.trim().parse()
+                && let Some((num, den)) = tb.trim().split_once('/')
+                && let (Ok(num), Ok(den)) = (num.parse::<f64>(), den.parse::<f64>())
+            {
+                time_bases.insert(stream, num / den);
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [stream, _, pts, frame_duration, _, hash] = fields[..] else {
+            continue;
+        };
+        let (Ok(stream), Ok(pts), Ok(frame_duration)) = (
+            stream.parse::<u32>(),
+            pts.parse::<i64>(),
+            frame_duration.parse::<i64>(),
+        ) else {
+            continue;
+        };
+        let time_base = time_bases.get(&stream).copied().unwrap_or(1.0);
+        streams.entry(stream).or_default().push(Unit {
+            start: start + pts as f64 * time_base,
+            duration: frame_duration as f64 * time_base,
+            hash: hash.to_string(),
+        });
+    }
+    streams
+}
+
+impl VersionDiff {
+    /// One numbered event per range with timestamps in both versions
+    pub fn to_edl(&self) -> String {
+        let mut edl = format!("TITLE: {} to {}\n", self.old, self.new);
+        writeln!(
+            edl,
+            "* {} edits, {} to {}\n",
+            self.edits.len(),
+            timestamp(self.old_duration),
+            timestamp(self.new_duration)
+        )
+        .unwrap();
+        for (number, edit) in self.edits.iter().enumerate() {
+            let range = |start: f64, end: f64| {
+                if start == end {
+                    format!("{}{}", timestamp(start), " ".repeat(15))
+                } else {
+                    format!("{} - {}", timestamp(start), timestamp(end))
+                }
+            };
+            writeln!(
+                edl,
+                "{:03}  {:<8}  old {}  new {}  by {}",
+                number + 1,
+                edit.kind.as_ref().to_uppercase(),
+                range(edit.old_start, edit.old_end),
+                range(edit.new_start, edit.new_end),
+                edit.resolution.as_ref()
+            )
+            .unwrap();
+        }
+        edl
+    }
+
+    pub fn write(&self) -> SResult<()> {
+        let diffs_root = db_path([VERSION_DIFFS_NAME]);
+        std::fs::create_dir_all(&diffs_root).map_err(SError::io(&diffs_root))?;
+        let stem = format!("{}..{}", self.old, self.new);
+        let json_path = diffs_root.join(format!("{stem}.json"));
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&json_path))?;
+        std::fs::write(&json_path, raw).map_err(SError::io(&json_path))?;
+        let edl_path = diffs_root.join(format!("{stem}.edl"));
+        std::fs::write(&edl_path, self.to_edl()).map_err(SError::io(&edl_path))?;
+        info!("wrote {} and {}", json_path.display(), edl_path.display());
+        Ok(())
+    }
+}
+
+/// 01:02:03.450
+fn timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
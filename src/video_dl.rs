@@ -0,0 +1,401 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::crawl::CrawlNode;
+use crate::downloader::{VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::failure::FailureClass;
+use crate::global_config::GlobalConfig;
+use crate::utils::shell_quote;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir, read_dir, remove_file};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::LazyLock;
+use strum::AsRefStr;
+use tracing::{trace, warn};
+
+/// Persisted per video in [DOWNLOAD_STATE_NAME], reconciled with the directory contents
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize, AsRefStr)]
+#[serde(tag = "state", content = "reason", rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum VideoState {
+    /// No directory, an empty one, or nothing but bookkeeping
+    New,
+    /// A download run claimed it and hasn't reported back
+    InProgress,
+    /// Leftovers of an interrupted or incomplete download, safe to resume
+    Partial(String),
+    Complete,
+    /// The last attempt failed, will be retried
+    Failed(String),
+    /// The media failed verification, removed and downloaded again
+    Corrupt(String),
+    /// Set by hand, not downloaded until released
+    Quarantined(String),
+    /// Known to be gone for good, never downloaded
+    Unavailable(String),
+}
+
+impl VideoState {
+    pub fn needs_download(&self) -> bool {
+        !matches!(
+            self,
+            VideoState::Complete | VideoState::Quarantined(_) | VideoState::Unavailable(_)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VideoRecord {
+    pub state: VideoState,
+    pub updated: DateTime<Utc>,
+    /// Download runs that claimed this video
+    pub attempts: u32,
+    /// Class of the last failed attempt
+    #[serde(default)]
+    pub failure: Option<FailureClass>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct DownloadStates {
+    videos: BTreeMap<String, VideoRecord>,
+}
+
+impl DownloadStates {
+    pub fn load() -> SResult<Self> {
+        let state_path = db_path([DOWNLOAD_STATE_NAME]);
+        if !state_path.exists() {
+            return Ok(Self::default());
+        }
+        let mut raw = std::fs::read(&state_path).map_err(SError::io(&state_path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(&state_path))
+    }
+
+    /// Write then rename so a kill mid-write keeps the previous record
+    pub fn save(&self) -> SResult<()> {
+        let state_path = db_path([DOWNLOAD_STATE_NAME]);
+        let temp_path = state_path.with_extension("json.tmp");
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&state_path))?;
+        std::fs::write(&temp_path, raw).map_err(SError::io(&temp_path))?;
+        std::fs::rename(&temp_path, &state_path).map_err(SError::io(&state_path))
+    }
+
+    pub fn set(&mut self, video_id: &str, state: VideoState) {
+        let record = self
+            .videos
+            .entry(video_id.to_string())
+            .or_insert_with(|| VideoRecord {
+                state: VideoState::New,
+                updated: Utc::now(),
+                attempts: 0,
+                failure: None,
+            });
+        if state == VideoState::InProgress {
+            record.attempts += 1;
+        }
+        record.state = state;
+        record.updated = Utc::now();
+    }
+
+    /// None after a success
+    pub fn record_failure(&mut self, video_id: &str, failure: Option<FailureClass>) {
+        if let Some(record) = self.videos.get_mut(video_id) {
+            record.failure = failure;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &VideoRecord)> {
+        self.videos.iter()
+    }
+
+    /// Back to whatever the directory says
+    pub fn forget(&mut self, video_id: &str) {
+        self.videos.remove(video_id);
+    }
+
+    pub fn get(&self, video_id: &str) -> Option<&VideoRecord> {
+        self.videos.get(video_id)
+    }
+
+    /// Hand set states and failed verification win, then a complete directory, then what the
+    /// last run recorded
+    pub fn state_of(&self, backend: &DownloaderBackend, video_id: &str) -> SResult<VideoState> {
+        let dir_state = inspect_video_dir(backend, video_id)?;
+        let recorded = self.videos.get(video_id).map(|r| &r.state);
+        Ok(match recorded {
+            Some(
+                state @ (VideoState::Quarantined(_)
+                | VideoState::Unavailable(_)
+                | VideoState::Corrupt(_)),
+            ) => state.clone(),
+            _ if dir_state == VideoState::Complete => dir_state,
+            Some(state @ (VideoState::InProgress | VideoState::Failed(_))) => state.clone(),
+            _ => dir_state,
+        })
+    }
+}
+
+/// Read only, does not create the directory. Only gives New, Partial, or Complete
+pub fn inspect_video_dir(backend: &DownloaderBackend, video_id: &str) -> SResult<VideoState> {
+    let spec = backend.artifacts();
+    let video_root = db_path([VIDEO_DL_NAME, video_id]);
+    if !video_root.exists() {
+        return Ok(VideoState::New);
+    }
+    let mut child_names = list_video_dir(video_id)?;
+
+    // a failed native download leaves its playlists and checksums behind
+    child_names.retain(|v| !spec.is_bookkeeping(v) && v != DONE_MARKER_NAME);
+
+    let leftovers: Vec<&String> = child_names.iter().filter(|v| is_partial_name(v)).collect();
+    if !leftovers.is_empty() {
+        let leftovers: Vec<&str> = leftovers.iter().map(|v| v.as_str()).collect();
+        trace!("partial {video_id} {}", leftovers.join(","));
+        return Ok(VideoState::Partial(format!(
+            "leftover {}",
+            leftovers.join(",")
+        )));
+    }
+
+    child_names.retain(|v| v != YTDL_LOG_NAME);
+    if child_names.is_empty() {
+        return Ok(if video_root.join(YTDL_LOG_NAME).exists() {
+            VideoState::Partial("only the log of an earlier attempt".into())
+        } else {
+            VideoState::New
+        });
+    }
+
+    let missing = spec.missing_required(&child_names);
+    if !missing.is_empty() {
+        let missing: Vec<&str> = missing.iter().map(|kind| kind.as_ref()).collect();
+        return Ok(VideoState::Partial(format!(
+            "missing {}",
+            missing.join(",")
+        )));
+    }
+    let unknown: Vec<&str> = child_names
+        .iter()
+        .filter(|v| spec.classify(v).is_none())
+        .map(|v| v.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        warn!(
+            "unknown files alongside complete {video_id}: {}",
+            unknown.join(",")
+        );
+    }
+    Ok(VideoState::Complete)
+}
+
+/// File names directly in the video directory
+pub fn list_video_dir(video_id: &str) -> SResult<Vec<String>> {
+    let video_root = db_path([VIDEO_DL_NAME, video_id]);
+    read_dir(&video_root)
+        .map_err(SError::io(&video_root))?
+        .map(|v| v.map(|v| v.file_name().to_string_lossy().to_string()))
+        .try_collect()
+        .map_err(SError::io(&video_root))
+}
+
+/// Video directories under vid-dl, without the dated versions of re-checked videos
+pub fn list_video_ids() -> SResult<Vec<String>> {
+    let vid_root = db_path([VIDEO_DL_NAME]);
+    if !vid_root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut video_ids: Vec<String> = read_dir(&vid_root)
+        .map_err(SError::io(&vid_root))?
+        .map(|v| v.map(|v| v.path()))
+        .try_collect::<Vec<_>>()
+        .map_err(SError::io(&vid_root))?
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+        .filter(|name| !name.contains('@'))
+        .collect();
+    video_ids.sort();
+    Ok(video_ids)
+}
+
+/// One video for the download stage or the exported script
+pub struct DownloadJob {
+    /// Directory and state record name
+    pub video_id: String,
+    /// Differs from [Self::video_id] for a re-downloaded version
+    pub bc_video_id: String,
+    pub title: String,
+    /// Absolute, the script cd's into it
+    pub video_root: PathBuf,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+pub const YTDL_LOG_NAME: &str = "ytdl.log";
+pub const DOWNLOAD_STATE_NAME: &str = "download-state.json";
+/// How youtube-dl, yt-dlp, ffmpeg, and our own temporaries end, e.g. .part-Frag3 or .temp.mp4.
+/// Only the end, a title may well say ".party"
+static PARTIAL_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\.part(-Frag\d+)?|\.ytdl|\.tmp|\.temp\.[A-Za-z0-9]{1,5})$").unwrap()
+});
+/// Written after a successful download so script reruns skip the video
+pub const DONE_MARKER_NAME: &str = ".eagle-done";
+
+/// A download still in progress or abandoned halfway
+pub fn is_partial_name(name: &str) -> bool {
+    PARTIAL_NAME.is_match(name)
+}
+
+impl DownloadJob {
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command.current_dir(&self.video_root);
+        command
+    }
+
+    /// Skips when the success marker of a previous run exists, needs `set -o pipefail`
+    pub fn script_lines(&self) -> Vec<String> {
+        let ytdl_args: Vec<String> = self.args.iter().map(|arg| shell_quote(arg)).collect();
+        vec![
+            format!("cd -- {}", shell_quote(&self.video_root.to_string_lossy())),
+            format!("if [ -e {DONE_MARKER_NAME} ]; then"),
+            format!(
+                "  echo {}",
+                shell_quote(&format!("skip {} done in a previous run", self.video_id))
+            ),
+            "else".into(),
+            format!("  printf '%s\\n' {}", shell_quote(&self.title)),
+            format!(
+                "  {} {} 2>&1 | tee {YTDL_LOG_NAME}",
+                shell_quote(&self.program),
+                ytdl_args.join(" "),
+            ),
+            format!("  touch {DONE_MARKER_NAME}"),
+            "  sleep 20".into(), // Be a nice scraper
+            "fi".into(),
+        ]
+    }
+}
+
+/// Otherwise youtube-dl says it has already been downloaded and the script skips it
+fn remove_corrupt_media(
+    backend: &DownloaderBackend,
+    video_id: &str,
+    reason: &str,
+    dry_run: bool,
+) -> SResult<()> {
+    let spec = backend.artifacts();
+    for name in list_video_dir(video_id)? {
+        if spec.classify(&name) != Some(ArtifactKind::Media) && name != DONE_MARKER_NAME {
+            continue;
+        }
+        let media_path = db_path([VIDEO_DL_NAME, video_id, &name]);
+        if dry_run {
+            println!("dry-run: would remove {} ({reason})", media_path.display());
+        } else {
+            warn!("removing {} ({reason})", media_path.display());
+            remove_file(&media_path).map_err(SError::io(&media_path))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn load_youtube_dl(
+    global_config: &GlobalConfig,
+    states: &DownloadStates,
+    video_thing: &CrawlNode,
+    dry_run: bool,
+) -> SResult<Option<DownloadJob>> {
+    let video_id = &video_thing.id;
+    /*
+    Shockingly the backend Video ID is the public ID.
+    This site `echo qrirybcre.bar.npprqb.gi | tr 'N-ZA-Mn-za-m' 'A-Za-z'` (rot13)
+    is apparently simply a (super complex) subscription manager.
+
+    I've paid for this content already so it's fine.
+    */
+    let video_root = db_path([VIDEO_DL_NAME, video_id]);
+    if !video_root.exists() && !dry_run {
+        create_dir(&video_root).map_err(SError::io(&video_root))?;
+    }
+
+    let backend = &global_config.downloader;
+    let state = states.state_of(backend, video_id)?;
+    if let VideoState::Corrupt(reason) = &state {
+        remove_corrupt_media(backend, video_id, reason, dry_run)?;
+    }
+    if state.needs_download() {
+        Ok(Some(download_job(
+            global_config,
+            video_id,
+            video_id,
+            video_thing.title(),
+        )?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// `dir_name` is the video id, or a dated version of it next to the original
+pub fn download_job(
+    global_config: &GlobalConfig,
+    dir_name: &str,
+    bc_video_id: &str,
+    title: &str,
+) -> SResult<DownloadJob> {
+    let backend = &global_config.downloader;
+    let video_root = db_path([VIDEO_DL_NAME, dir_name]);
+    let account_id = &global_config.bc_account_id;
+    let final_url = format!(
+        "https://players.brightcove.net/{account_id}/default_default/index.html?videoId={bc_video_id}"
+    );
+    // may not exist yet in dry-run mode
+    let full_root = std::path::absolute(&video_root).map_err(SError::io(&video_root))?;
+    let args = backend.args(
+        &final_url,
+        &full_root.to_string_lossy(),
+        bc_video_id,
+        title,
+        &global_config.quality,
+        global_config.subtitles,
+    );
+    Ok(DownloadJob {
+        video_id: dir_name.to_string(),
+        bc_video_id: bc_video_id.to_string(),
+        title: title.into(),
+        video_root: full_root,
+        program: backend.program().into(),
+        args,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_names() {
+        for name in [
+            "6300000000001.mp4.part",
+            "6300000000001.f137.mp4.part",
+            "6300000000001.mp4.part-Frag12",
+            "6300000000001.mp4.ytdl",
+            "6300000000001.temp.mp4",
+            "download-state.json.tmp",
+        ] {
+            assert!(is_partial_name(name), "{name}");
+        }
+        for name in [
+            "Pool.party.mp4",
+            "The .tmp folder.mp4",
+            "6300000000001.part.info.json",
+            "Notes.temp.and.more.mkv",
+            "6300000000001.mp4",
+        ] {
+            assert!(!is_partial_name(name), "{name}");
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::backend::{ArtifactKind, DownloaderBackend};
+use crate::downloader::{VIDEO_DL_NAME, db_path};
+use crate::err::{SError, SResult};
+use crate::quality::{QualityMode, QualityPolicy};
+use crate::recheck::{VodFingerprint, VodVersion};
+use crate::subtitles::TextTrack;
+use crate::video_dl::list_video_dir;
+use serde::{Deserialize, Serialize};
+use simd_json::BorrowedValue;
+use simd_json::prelude::{
+    ValueAsArray, ValueAsScalar, ValueObjectAccess, ValueObjectAccessAsScalar,
+};
+use std::path::Path;
+use tracing::warn;
+
+/// Our own per video metadata, next to the media
+pub const VIDEO_META_NAME: &str = "eagle.json";
+
+/// One encoding of a video, as listed by Brightcove, the HLS master playlist, or youtube-dl
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct Rendition {
+    /// hls, hls-audio, mp4, or ytdl
+    pub source: String,
+    pub format_id: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Bits per second
+    pub bitrate: Option<u64>,
+    /// Bytes, when the source lists it
+    #[serde(default)]
+    pub size: Option<u64>,
+    pub codecs: Option<String>,
+    pub container: Option<String>,
+    pub audio_only: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct VideoMeta {
+    /// The policy in effect when the renditions were chosen
+    #[serde(default)]
+    pub quality_policy: Option<String>,
+    #[serde(default)]
+    pub chosen: Vec<Rendition>,
+    /// Everything that was on offer
+    #[serde(default)]
+    pub inventory: Vec<Rendition>,
+    /// What an earlier download chose, when it differs
+    #[serde(default)]
+    pub previous_chosen: Vec<Rendition>,
+    #[serde(default)]
+    pub text_tracks: Vec<TextTrack>,
+    /// As of the last re-check, or the download
+    #[serde(default)]
+    pub fingerprint: Option<VodFingerprint>,
+    /// Edited copies downloaded after the re-check found a difference
+    #[serde(default)]
+    pub versions: Vec<VodVersion>,
+    /// Posters, info.json, and text tracks the last download could not save, with the reason
+    #[serde(default)]
+    pub missing_assets: Vec<String>,
+}
+
+impl Rendition {
+    /// Short name for files and logs, like 720p-2000k
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if self.audio_only {
+            parts.push("audio".to_string());
+        } else if let Some(height) = self.height {
+            parts.push(format!("{height}p"));
+        }
+        if let Some(bitrate) = self.bitrate {
+            parts.push(format!("{}k", bitrate / 1000));
+        }
+        if parts.is_empty() {
+            parts.push(self.format_id.clone().unwrap_or_else(|| "unknown".into()));
+        }
+        parts.join("-")
+    }
+}
+
+impl VideoMeta {
+    pub fn load(video_id: &str) -> SResult<Self> {
+        let meta_path = db_path([VIDEO_DL_NAME, video_id, VIDEO_META_NAME]);
+        if !meta_path.exists() {
+            return Ok(Self::default());
+        }
+        let mut raw = std::fs::read(&meta_path).map_err(SError::io(&meta_path))?;
+        simd_json::from_slice(&mut raw).map_err(SError::json(&meta_path))
+    }
+
+    pub fn save(&self, video_id: &str) -> SResult<()> {
+        let meta_path = db_path([VIDEO_DL_NAME, video_id, VIDEO_META_NAME]);
+        let raw = simd_json::to_vec_pretty(self).map_err(SError::json(&meta_path))?;
+        std::fs::write(&meta_path, raw).map_err(SError::io(&meta_path))
+    }
+
+    /// Keep what an earlier download chose if this one chose differently
+    pub fn record_renditions(
+        video_id: &str,
+        quality_policy: String,
+        chosen: Vec<Rendition>,
+        inventory: Vec<Rendition>,
+    ) -> SResult<()> {
+        let mut meta = Self::load(video_id)?;
+        if !meta.chosen.is_empty() && meta.chosen != chosen {
+            let labels = |renditions: &[Rendition]| {
+                renditions
+                    .iter()
+                    .map(Rendition::label)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            warn!(
+                "{video_id} rendition changed from {} to {}",
+                labels(&meta.chosen),
+                labels(&chosen)
+            );
+            meta.previous_chosen = std::mem::take(&mut meta.chosen);
+        }
+        meta.quality_policy = Some(quality_policy);
+        meta.chosen = chosen;
+        meta.inventory = inventory;
+        meta.save(video_id)
+    }
+
+    /// Replaces what an earlier download was missing
+    pub fn record_missing_assets(video_id: &str, missing_assets: Vec<String>) -> SResult<()> {
+        let mut meta = Self::load(video_id)?;
+        meta.missing_assets = missing_assets;
+        meta.save(video_id)
+    }
+}
+
+/// After youtube-dl or yt-dlp, what they picked and what was on offer according to the info.json
+pub fn record_ytdl_renditions(
+    backend: &DownloaderBackend,
+    quality: &QualityPolicy,
+    video_id: &str,
+) -> SResult<()> {
+    let child_names = list_video_dir(video_id)?;
+    let Some(info_name) = backend
+        .artifacts()
+        .find(&child_names, ArtifactKind::InfoJson)
+    else {
+        warn!("{video_id} has no info.json, rendition not recorded");
+        return Ok(());
+    };
+    let info_path = db_path([VIDEO_DL_NAME, video_id, info_name]);
+    let (mut chosen, inventory) = renditions_from_info_json(&info_path)?;
+    if quality.mode == QualityMode::KeepAll {
+        chosen = inventory
+            .iter()
+            .filter(|r| !r.audio_only)
+            .cloned()
+            .collect();
+    }
+    VideoMeta::record_renditions(video_id, quality.describe(), chosen, inventory)
+}
+
+fn renditions_from_info_json(info_path: &Path) -> SResult<(Vec<Rendition>, Vec<Rendition>)> {
+    let mut raw = std::fs::read(info_path).map_err(SError::io(info_path))?;
+    let json: BorrowedValue =
+        simd_json::to_borrowed_value(&mut raw).map_err(SError::json(info_path))?;
+
+    let chosen = ytdl_rendition(&json);
+    let inventory = json
+        .get("formats")
+        .and_then(|v| v.as_array())
+        .map(|formats| formats.iter().map(ytdl_rendition).collect())
+        .unwrap_or_default();
+    Ok((vec![chosen], inventory))
+}
+
+fn ytdl_rendition(format: &BorrowedValue) -> Rendition {
+    let codec = |key: &str| format.get_str(key).filter(|c| *c != "none");
+    let vcodec = codec("vcodec");
+    let acodec = codec("acodec");
+    let codecs: Vec<&str> = vcodec.iter().chain(acodec.iter()).copied().collect();
+    Rendition {
+        source: "ytdl".into(),
+        format_id: format.get_str("format_id").map(String::from),
+        width: format.get_u64("width").map(|v| v as u32),
+        height: format.get_u64("height").map(|v| v as u32),
+        // kbps, sometimes fractional
+        bitrate: format
+            .get("tbr")
+            .and_then(|tbr| tbr.cast_f64())
+            .map(|tbr| (tbr * 1000.0) as u64),
+        size: format.get_u64("filesize"),
+        codecs: (!codecs.is_empty()).then(|| codecs.join(",")),
+        container: format.get_str("ext").map(String::from),
+        audio_only: vcodec.is_none() && acodec.is_some(),
+    }
+}